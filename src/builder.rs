@@ -1,40 +1,143 @@
-use std::collections::{HashMap, HashSet};
+use std::cmp::min;
+use std::collections::HashMap;
 use std::hash::Hash;
-use std::cmp::{max};
-use crate::MultiMarkov;
+use std::io::BufRead;
+#[cfg(feature = "flate2")]
+use std::io::{BufReader, Read};
+#[cfg(feature = "flate2")]
+use flate2::read::GzDecoder;
+use std::ops::Range;
+use rand::{RngCore, SeedableRng};
+use rand::rngs::StdRng;
+#[cfg(feature = "rayon")]
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use crate::{MultiMarkov, StateMap, StateSet, Weight, thread_seeded_rng};
+use crate::error::MultiMarkovError;
+use crate::generator::DecodeMode;
+use crate::smoothing::{AbsoluteDiscount, AutoPrior, FlatPrior, KneserNey, PriorMap, Smoothing, WittenBell};
+
+/// The smoothing, if any, applied to the model when it is built.
+enum SmoothingMethod<T> {
+    PriorMap(PriorMap<T>), // not boxed, so the builder is `Send` whenever `T` is
+    LazyPrior(f64),
+    Custom(Box<dyn Smoothing<T> + Send + Sync>),
+}
 
 pub struct MultiMarkovBuilder<T>
     where T: Eq + Hash + Clone
 {
-    pub markov_chain: HashMap<Vec<T>,HashMap<T,f64>>,
-    pub known_states: HashSet<T>,
+    pub markov_chain: StateMap<Vec<T>,StateMap<T,f64>>,
+    pub known_states: StateSet<T>,
+    initial_states: StateMap<T,usize>, // how many trained sequences began with each state
     order: i32,
-    prior: Option<f64>,
+    smoothing: Option<SmoothingMethod<T>>,
+    explicit_prior: bool, // the smoothing is a prior chosen explicitly, or was until Witten-Bell replaced it
+    witten_bell: bool, // the smoothing is Witten-Bell
+    rng: Option<StdRng>,
+    start_sentinel: Option<T>,
+    sentinel: Option<T>, // the end sentinel
+    max_length: usize,
+    min_length: usize,
+    repetition_penalty: Option<(f64, usize)>,
+    decode_mode: DecodeMode,
+    unigrams: bool,
+    marginal_fallback: bool,
+    reversed: bool,
+    stable_order: bool,
+    strict_states: bool,
+    backoff_penalty: f64,
+    training_sequences: Option<StateMap<Vec<T>,usize>>, // how many times each sequence was trained
+    observations: usize,
+    entropy_tracking: Option<(f64, f64)>, // total log loss and total weight of the transitions scored
+    interning: bool,
 }
 
 impl<T> MultiMarkovBuilder<T>
     where T: Eq + Hash + Clone
 {
+    /// Weights smaller than this are removed by `decay`.
+    pub const DECAY_EPSILON: f64 = 1e-6;
+
     /// Instantiate a new builder.
     pub fn new() -> Self {
         Self {
-            markov_chain: HashMap::new(),
-            known_states: HashSet::new(),
+            markov_chain: StateMap::default(),
+            known_states: StateSet::default(),
+            initial_states: StateMap::default(),
             order: MultiMarkov::<T>::DEFAULT_ORDER,
-            prior: Some(MultiMarkov::<T>::DEFAULT_PRIOR),
+            smoothing: Some(SmoothingMethod::Custom(Box::new(FlatPrior(MultiMarkov::<T>::DEFAULT_PRIOR)))),
+            explicit_prior: false,
+            witten_bell: false,
+            rng: None,
+            start_sentinel: None,
+            sentinel: None,
+            max_length: MultiMarkov::<T>::DEFAULT_MAX_LENGTH,
+            min_length: 0,
+            repetition_penalty: None,
+            decode_mode: DecodeMode::Sample,
+            unigrams: false,
+            marginal_fallback: false,
+            reversed: false,
+            stable_order: false,
+            strict_states: false,
+            backoff_penalty: 1.0,
+            training_sequences: None,
+            observations: 0,
+            entropy_tracking: None,
+            interning: false,
+        }
+    }
+
+    /// Start a builder from transition counts computed elsewhere (e.g. by an external n-gram
+    /// counting tool) instead of by training: for each context, the count of each state observed
+    /// following it, as in `markov_chain`.  Every following state becomes a known state.  The
+    /// counts should include every shorter tail of each context too, as training records them,
+    /// or the model won't be able to back off to them.  Configure and build it as usual.
+    ///
+    /// Returns an `Err` if `order` is negative, if any context is longer than `order`, or if any
+    /// count is zero, negative or `NaN`.
+    pub fn from_counts(order: i32, counts: StateMap<Vec<T>,StateMap<T,f64>>) -> Result<Self,MultiMarkovError> {
+        let mut builder = Self::new().try_with_order(order)?;
+        for (context, transitions) in counts {
+            if context.len() > order as usize { return Err(MultiMarkovError::ContextTooLong(context.len(), order)); }
+            if let Some(count) = transitions.values().find(|count| count.is_nan() || **count <= 0.0) {
+                return Err(MultiMarkovError::InvalidWeight(*count));
+            }
+            if transitions.is_empty() { continue; }
+            builder.known_states.extend(transitions.keys().cloned());
+            builder.markov_chain.insert(context, transitions);
         }
+        Ok(builder)
     }
 
-    /// Specify the "order" of the Markov model.  Must be a positive integer.
+    /// Specify the "order" of the Markov model.  Must be a non-negative integer.
     /// We recommend small values from about 1 to 3.  Higher values will make the procedurally
     /// generated data more similar to the training data, less random, and will make the process
     /// slower and require more memory.
     ///
+    /// An order of 0 gives a baseline model that ignores context entirely: it only counts how often
+    /// each state occurs (except a leading sentinel), under the empty sequence `[]`, and draws
+    /// states by their overall frequency.
+    ///
     /// The default is `MultiMarkov::DEFAULT_ORDER`
-    pub fn with_order(mut self, order: i32) -> Self {
-        assert!(order>0,"Order must be an integer greater than zero.");
+    ///
+    /// The order must be set before training: the model only records contexts up to the order in
+    /// effect when each sequence is trained, so changing it afterwards would make lookups use
+    /// context lengths that were never recorded.
+    ///
+    /// Panics if `order` is negative, or if it would change the order after training has
+    /// begun; use `try_with_order` to get an `Err` instead.
+    pub fn with_order(self, order: i32) -> Self {
+        self.try_with_order(order).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `with_order`, but returns an `Err` instead of panicking if `order` is negative or
+    /// the model has already been trained with a different order.
+    pub fn try_with_order(mut self, order: i32) -> Result<Self,MultiMarkovError> {
+        if order < 0 { return Err(MultiMarkovError::InvalidOrder(order)); }
+        if order != self.order && !self.markov_chain.is_empty() { return Err(MultiMarkovError::OrderChangedAfterTraining); }
         self.order = order;
-        self
+        Ok(self)
     }
 
     /// Specifies the "prior probability" of transition from any known state to any other known state,
@@ -44,9 +147,43 @@ impl<T> MultiMarkovBuilder<T>
     /// The default is `MultiMarkov::DEFAULT_PRIOR`
     pub fn with_prior(mut self, prior: f64) -> Self {
         if prior==0.0 {
-            self.prior = None;
+            self.set_smoothing(None, false);
         } else {
-            self.prior = Some(prior);
+            self.set_smoothing(Some(SmoothingMethod::Custom(Box::new(FlatPrior(prior)))), true);
+        }
+        self
+    }
+
+    /// Like `with_prior`, but the prior is worked out from the training data when the model is
+    /// built, instead of being a fixed number: it is the smallest observed count divided by the
+    /// number of known states (see `AutoPrior`), so it shrinks as the alphabet grows and scales
+    /// with weighted training.  This gives a reasonable default without picking a number by hand.
+    pub fn with_auto_prior(mut self) -> Self {
+        self.set_smoothing(Some(SmoothingMethod::Custom(Box::new(AutoPrior))), true);
+        self
+    }
+
+    /// Like `with_prior`, but with a different prior for each state, for when some states (rare
+    /// punctuation, say) should be much less likely to appear out of nowhere than others.  States
+    /// missing from `priors` get the `default` prior, and a prior of 0.0 means the state is never
+    /// added to a context it wasn't observed following.
+    pub fn with_prior_map(mut self, priors: HashMap<T,f64>, default: f64) -> Self {
+        self.set_smoothing(Some(SmoothingMethod::PriorMap(PriorMap { priors, default })), true);
+        self
+    }
+
+    /// Like `with_prior`, but the prior is applied lazily, when the model is queried, instead of
+    /// being stored in the model as a weight for every known state in every context.  This keeps
+    /// the model small when there are many known states (the eager prior needs an entry for every
+    /// context and state pair), at the cost of filling in the prior on every draw or query, which
+    /// takes time in proportion to the number of known states.  Draws and probabilities are the
+    /// same either way, but `markov_chain`, `iter_transitions` and `to_dot` show only the observed
+    /// transitions.
+    pub fn with_lazy_prior(mut self, prior: f64) -> Self {
+        if prior==0.0 {
+            self.set_smoothing(None, false);
+        } else {
+            self.set_smoothing(Some(SmoothingMethod::LazyPrior(prior)), true);
         }
         self
     }
@@ -54,94 +191,945 @@ impl<T> MultiMarkovBuilder<T>
     /// Specifies that there will be no use of "prior probability" in this model.  The only state
     /// transitions possible will be those seen in the training data.
     pub fn without_prior(mut self) -> Self {
-        self.prior = None;
+        self.set_smoothing(None, false);
+        self
+    }
+
+    /// Use interpolated Kneser-Ney smoothing instead of a flat prior.  When the model is built, the
+    /// `discount` is subtracted from every observed transition count, and the freed weight is
+    /// redistributed over all known states according to lower-order "continuation" probabilities:
+    /// a state gets more weight if it has been seen following many *different* contexts, rather
+    /// than merely often.  The weights in each model are then renormalized to sum to 1, which they
+    /// otherwise wouldn't where counts are smaller than the discount (e.g. in weighted training).
+    ///
+    /// This replaces the flat prior: `with_prior` and `with_kneser_ney` are alternatives, and
+    /// whichever is called last is the one that gets applied.  Typical discounts are around 0.75.
+    pub fn with_kneser_ney(mut self, discount: f64) -> Self {
+        assert!(discount>0.0 && discount<=1.0,"Discount must be greater than zero and at most one.");
+        self.set_smoothing(Some(SmoothingMethod::Custom(Box::new(KneserNey { discount }))), false);
+        self
+    }
+
+    /// Use absolute discounting instead of a flat prior.  When the model is built, the `discount`
+    /// is subtracted from every observed transition count, and the freed weight is shared equally
+    /// between the known states that were never seen following that context.  Contexts that have
+    /// been seen followed by every known state are left as they are.
+    ///
+    /// Like `with_kneser_ney`, this replaces the flat prior.  Panics if the discount is not greater than zero.  The
+    /// discount must also be smaller than the smallest observed count, which would otherwise be
+    /// left with no weight or negative weight: `try_build` returns
+    /// `MultiMarkovError::InvalidDiscount` if it isn't, and `build` panics.
+    pub fn with_absolute_discounting(mut self, discount: f64) -> Self {
+        assert!(discount>0.0,"Discount must be greater than zero.");
+        self.set_smoothing(Some(SmoothingMethod::Custom(Box::new(AbsoluteDiscount(discount)))), false);
+        self
+    }
+
+    /// Use Witten-Bell smoothing instead of a flat prior.  When the model is built, each context
+    /// sets aside some probability for the known states never seen following it: the more
+    /// *distinct* states were seen following a context, the more it sets aside.  Unlike a flat
+    /// prior, this adapts to each context, and the weights in each model then sum to 1.
+    ///
+    /// It works on the raw training counts, so it replaces the default flat prior, but it can't
+    /// replace a prior chosen explicitly (with `with_prior` and the like) just before it:
+    /// `try_build` then returns `MultiMarkovError::ConflictingSmoothing`, and `build` panics.  Like
+    /// any other smoothing, it is replaced by whichever smoothing method is called after it.
+    pub fn with_witten_bell(mut self) -> Self {
+        self.witten_bell = true;
+        self.smoothing = Some(SmoothingMethod::Custom(Box::new(WittenBell)));
+        self
+    }
+
+    /// Use a custom smoothing strategy, applied to the trained counts when the model is built.
+    /// Like the other smoothing methods, this replaces the flat prior.
+    pub fn with_smoothing(mut self, strategy: impl Smoothing<T> + Send + Sync + 'static) -> Self {
+        self.set_smoothing(Some(SmoothingMethod::Custom(Box::new(strategy))), false);
+        self
+    }
+
+    /// Replace the smoothing (and with it any Witten-Bell smoothing), noting whether the new
+    /// smoothing is a prior chosen explicitly.
+    fn set_smoothing(&mut self, smoothing: Option<SmoothingMethod<T>>, explicit_prior: bool) {
+        self.smoothing = smoothing;
+        self.explicit_prior = explicit_prior;
+        self.witten_bell = false;
+    }
+
+    /// Seeds the model's random number generator with a fixed value, so that the same trained
+    /// model will produce the same draws every time.  Draws also depend on the order in which the
+    /// possible states are walked through, and the model's hash maps list them in an order that
+    /// differs between runs (and between models), so this turns on `with_stable_order` too.
+    ///
+    /// By default the generator is seeded from `rand::thread_rng()`.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = Some(StdRng::seed_from_u64(seed));
+        self.stable_order = true;
+        self
+    }
+
+    /// Makes draws walk through the possible states in a fixed order (by a hash that is the same in
+    /// every run), instead of the order the model's hash maps happen to list them in, which
+    /// differs between runs.  With a seed (see `with_seed`), the same training data then gives the
+    /// same draws every time, e.g. for snapshot tests; seeding turns this on by itself.  The order
+    /// depends only on how each state is hashed (by its `Hash` implementation, with a fixed
+    /// algorithm on every platform).  The states are sorted once, when the model is built.
+    pub fn with_stable_order(mut self) -> Self {
+        self.stable_order = true;
+        self
+    }
+
+    /// Specifies a penalty for backing off when scoring sequences with
+    /// `MultiMarkov::sequence_log_probability` (and so `sequence_probability`, `perplexity`,
+    /// `cross_entropy` and `classify`): each transition's probability is multiplied by `penalty`
+    /// once for every state dropped from its context because the longer context was never seen,
+    /// as in stupid backoff.  A sequence that only matches short contexts then scores lower than
+    /// one that matches at the full order.  `MultiMarkov::DEFAULT_BACKOFF_ALPHA` (0.4) is the
+    /// usual choice.
+    ///
+    /// Penalized scores are no longer probabilities that sum to 1, so the perplexity goes up by
+    /// a factor of `1 / penalty` for each backoff per transition, on average; compare perplexities
+    /// only between models with the same penalty.  The default is 1.0, i.e. no penalty.  Panics
+    /// if the penalty is not greater than zero and at most 1.
+    pub fn with_backoff_penalty(mut self, penalty: f64) -> Self {
+        assert!(penalty > 0.0 && penalty <= 1.0, "Backoff penalty must be greater than zero and at most 1.");
+        self.backoff_penalty = penalty;
+        self
+    }
+
+    /// Makes `MultiMarkov::try_random_next` and `MultiMarkov::try_transition_probability` return
+    /// `MultiMarkovError::UnknownState` when the sequence they are given contains a state never
+    /// seen in training, instead of quietly backing off past it.  This catches e.g. tokenization
+    /// that differs between training and querying.  By default the model is lenient, and those
+    /// methods always succeed.
+    pub fn with_strict_states(mut self) -> Self {
+        self.strict_states = true;
+        self
+    }
+
+    /// Seeds the model's random number generator from a user-supplied random number generator.
+    /// Passing in a seeded generator (for example a `rand::rngs::StdRng` created with
+    /// `seed_from_u64`) makes generation reproducible.  Like `with_seed`, this turns on
+    /// `with_stable_order`.
+    ///
+    /// By default the generator is seeded from `rand::thread_rng()`.
+    pub fn with_rng<R: RngCore>(mut self, rng: R) -> Self {
+        self.rng = Some(StdRng::from_rng(rng).expect("Could not seed the random number generator."));
+        self.stable_order = true;
+        self
+    }
+
+    /// Specifies sentinel states that mark the beginning and end of every sequence.  Each training
+    /// sequence will have `order` copies (at least one) of the `start` sentinel added to its start,
+    /// and the `end` sentinel added to its end, before it is trained, so that the model learns
+    /// which states begin and end a sequence, with contexts of the full order even at the start.
+    /// This lets `MultiMarkov::generate_sequence` generate whole sequences from scratch, starting
+    /// from the padded context, and know to stop when it draws the `end` sentinel.
+    ///
+    /// The sentinels should be values that never occur inside the training data, such as `'^'` and
+    /// `'$'` for a model of words.  Call this before `train`.
+    pub fn with_boundaries(mut self, start: T, end: T) -> Self {
+        self.start_sentinel = Some(start);
+        self.sentinel = Some(end);
+        self
+    }
+
+    /// Specifies one "sentinel" state that marks both the beginning and end of every sequence: a
+    /// shorthand for `with_boundaries(sentinel.clone(), sentinel)`.  One sentinel can serve as both
+    /// markers, since its position tells them apart: a context beginning with the sentinel can only
+    /// be the start of a sequence, and a sentinel drawn after other states can only be its end.
+    pub fn with_sentinel(self, sentinel: T) -> Self {
+        self.with_boundaries(sentinel.clone(), sentinel)
+    }
+
+    /// Specifies that training sequences consisting of a single state should be trained instead of
+    /// skipped, by recording the state under an "empty" (order-0) model, i.e. `vec![]`.  The
+    /// model falls back to this unigram distribution when no longer sequence matches.  By
+    /// default, single-state sequences are skipped as errors.
+    pub fn with_unigrams(mut self) -> Self {
+        self.unigrams = true;
+        self
+    }
+
+    /// Specifies that every observed state should also be counted in an "empty" (order-0) model,
+    /// i.e. `vec![]`, giving the overall frequency of each state in the training data.  The model
+    /// falls back to this marginal distribution as a last resort when no longer sequence matches,
+    /// so that generation can always continue, even from a state that was never followed by anything.
+    /// By default there is no fallback, and no model will be found for such sequences.
+    pub fn with_marginal_fallback(mut self) -> Self {
+        self.marginal_fallback = true;
+        self
+    }
+
+    /// Specifies that every sequence should be reversed before it is trained (or untrained), so
+    /// that the model learns which states *precede* a given sequence, for backward generation or
+    /// filling gaps together with a forward model.  Query the built model with sequences in reverse
+    /// order too: `random_next(&['c','b'])` draws a state likely to come before `['b','c']`, and
+    /// generated sequences come out back to front.
+    pub fn with_reversed_sequences(mut self) -> Self {
+        self.reversed = true;
+        self
+    }
+
+    /// Specifies that training should keep a running estimate of the model's entropy rate, read
+    /// with `current_entropy_rate`.  This costs extra lookups for every transition trained, so it
+    /// is off by default.  Call this before `train`; earlier training is not included.  Sequences
+    /// trained with `train_parallel` are counted in separate partial models, so they aren't scored.
+    ///
+    /// Each transition is scored with add-one smoothing at the longest context already seen: the
+    /// probability of state `s` after a context with total count `n` among `k` known states is
+    /// `(count(s) + 1) / (n + k + 1)` (with the extra one for a never-seen state), or `1 / (k + 1)`
+    /// if no tail of the preceding states has been seen yet.
+    pub fn with_entropy_tracking(mut self) -> Self {
+        self.entropy_tracking.get_or_insert((0.0, 0.0));
+        self
+    }
+
+    /// Counts each batch of training sequences (each call to `train` and the like) with every
+    /// state replaced by an integer ID, and only translates the counts back into states once the
+    /// batch is done.  Each observation then hashes small integers instead of a vector of states,
+    /// while each distinct context is translated once, so for heavy states such as words this
+    /// speeds up training on a batch in which the same contexts recur many times.  Where most
+    /// contexts are seen only once, the translation costs more than it saves, and for light states
+    /// like `char` there is nothing to save; measure on your data.  The trained counts are the same.
+    ///
+    /// Interning is skipped with `with_entropy_tracking`, which reads the counts after every
+    /// sequence, and by `train_parallel`, which trains a sequence at a time.
+    ///
+    /// The IDs only exist during training: `markov_chain` (and so the built model) holds the
+    /// states themselves, as usual.
+    pub fn with_interning(mut self) -> Self {
+        self.interning = true;
+        self
+    }
+
+    /// Specifies that the model should remember every sequence it is trained on, so that
+    /// `MultiMarkov::is_training_sequence` can tell whether a sequence merely repeats the training
+    /// data, and `MultiMarkov::generate_novel_sequences` can reject any that do.  This costs
+    /// memory in proportion to the size of the training data.  Call this before `train`;
+    /// sequences trained earlier are not remembered.
+    pub fn with_remembered_inputs(mut self) -> Self {
+        self.training_sequences.get_or_insert_with(StateMap::default);
+        self
+    }
+
+    /// Specifies the maximum length of sequences produced by `MultiMarkov::generate_sequence`.
+    ///
+    /// The default is `MultiMarkov::DEFAULT_MAX_LENGTH`
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Specifies the minimum length of sequences produced by `MultiMarkov::generate_sequence` (and
+    /// `generator` and `generate_sequence_until`).  Until a sequence is this long, neither the
+    /// sentinel nor any state that can only be followed by the sentinel (or by nothing) can be
+    /// drawn, so generation doesn't end early (though the state that reaches the minimum may be
+    /// one that ends it); the other states are drawn in proportion to their weights as usual.  If none of them can follow, generation gives up and the sequence is
+    /// shorter after all.
+    ///
+    /// The maximum length takes precedence over the minimum.  The default is 0, i.e. no minimum.
+    pub fn with_min_length(mut self, min_length: usize) -> Self {
+        self.min_length = min_length;
+        self
+    }
+
+    /// Specifies a repetition penalty for generated sequences, to discourage loops like "anana".
+    /// While generating (with `MultiMarkov::generator` or any of the `generate_*` methods), each
+    /// possible state's weight is divided by `penalty` once for every time that state appears in
+    /// the last `window` states of the sequence so far, including the starting sequence.
+    ///
+    /// Training and the model's probabilities are unaffected.  Panics if `penalty` is less than 1.
+    pub fn with_repetition_penalty(mut self, penalty: f64, window: usize) -> Self {
+        assert!(penalty >= 1.0, "Repetition penalty must be at least 1.");
+        self.repetition_penalty = Some((penalty, window));
+        self
+    }
+
+    /// Specifies how `MultiMarkov::generator` and the `generate_*` methods pick each state: by
+    /// drawing in proportion to the weights (`DecodeMode::Sample`, the default), greedily, with a
+    /// temperature, or with nucleus sampling.  A single generator can also override it with
+    /// `Generator::with_decode_mode`.  Panics if the mode's parameter is out of range, as the
+    /// corresponding `random_next_*` method would.
+    pub fn with_decode_mode(mut self, mode: DecodeMode) -> Self {
+        mode.validate();
+        self.decode_mode = mode;
+        self
+    }
+
+    /// Reserves room for at least `contexts` more contexts and `states` more known states, so that
+    /// training a large corpus of roughly known size doesn't repeatedly grow and rehash the
+    /// collections.  This is purely a performance hint and doesn't change the trained model.
+    pub fn reserve(mut self, contexts: usize, states: usize) -> Self {
+        self.markov_chain.reserve(contexts);
+        self.known_states.reserve(states);
         self
     }
 
     /// Ingest an iterator of sequences, adding the observed state transitions to the internal
-    /// statistical model.
-    pub fn train(mut self, sequences: impl Iterator<Item = Vec<T>>) -> Self {
+    /// statistical model.  Both the outer collection and each sequence may be any `IntoIterator`,
+    /// so training data can be streamed (e.g. from a file reader) without first collecting it
+    /// into nested vectors.
+    ///
+    /// Sequences that can't be trained (e.g. because they are too short) are skipped; use
+    /// `train_with_errors` to find out which ones.
+    pub fn train<S>(mut self, sequences: impl IntoIterator<Item = S>) -> Self
+        where S: IntoIterator<Item = T>
+    {
+        self.train_sequences(sequences);
+        self
+    }
+
+    /// Like `train`, but calls `on_error` for every sequence that is skipped, with the index of
+    /// the sequence in `sequences` and a description of the problem.  Returns
+    /// `MultiMarkovError::EmptyInput` if no sequence could be trained (including if there were
+    /// none), like `MultiMarkov::train`.  For example, to count the skipped sequences:
+    ///
+    /// ```
+    /// use multimarkov::MultiMarkov;
+    /// let mut skipped = 0;
+    /// let builder = MultiMarkov::<char>::builder()
+    ///     .train_with_errors(vec![vec!['a'], vec!['b','a','z']], |_index, _error| skipped += 1)
+    ///     .unwrap();
+    /// assert_eq!(skipped, 1);
+    /// ```
+    pub fn train_with_errors<S>(mut self, sequences: impl IntoIterator<Item = S>, on_error: impl FnMut(usize, MultiMarkovError)) -> Result<Self,MultiMarkovError>
+        where S: IntoIterator<Item = T>
+    {
+        let (success_count, _) = self.train_weighted_sequences(sequences.into_iter().map(|sequence| (sequence, 1.0)), on_error);
+        if success_count == 0 { return Err(MultiMarkovError::EmptyInput); }
+        Ok(self)
+    }
+
+    /// Like `train`, but each sequence comes with a weight: its transitions are counted `weight`
+    /// times instead of once, as if the sequence had appeared that many times in the training data.
+    /// Weights need not be whole numbers.  Sequences whose weight is not positive are skipped as
+    /// errors.
+    pub fn train_weighted<S>(mut self, sequences: impl IntoIterator<Item = (S, f64)>) -> Self
+        where S: IntoIterator<Item = T>
+    {
+        self.train_weighted_sequences(sequences, |_, _| ());
+        self
+    }
+
+    /// The number of observations recorded by training so far: how many times the count of a
+    /// (context, following state) transition was increased.  A sequence of `n` states trained at
+    /// order `k` records one observation per context of each following state, so compare this
+    /// before and after training a batch to check how much was ingested, or to report progress.
+    /// Merging adds the other builder's observations; untraining, pruning and decay don't change it.
+    pub fn observation_count(&self) -> usize {
+        self.observations
+    }
+
+    /// The model's running (prequential) estimate of its entropy rate, in nats per transition, if
+    /// built `with_entropy_tracking`: the average of `-ln p` over every transition trained since,
+    /// where `p` is the probability the model gave the transition just *before* training on its
+    /// sequence.  This tends to fall as the model learns to predict the data, so it can be checked
+    /// while streaming a large corpus to stop once it levels off.  Returns `None` if tracking is
+    /// off or nothing has been trained since it was turned on.
+    pub fn current_entropy_rate(&self) -> Option<f64> {
+        match self.entropy_tracking {
+            Some((log_loss, weight)) if weight > 0.0 => Some(log_loss / weight),
+            _ => None,
+        }
+    }
+
+    /// Train on each sequence in turn, returning the number of sequences successfully trained and
+    /// the number that were skipped because of errors.
+    pub(crate) fn train_sequences<S>(&mut self, sequences: impl IntoIterator<Item = S>) -> (usize, usize)
+        where S: IntoIterator<Item = T>
+    {
+        self.train_weighted_sequences(sequences.into_iter().map(|sequence| (sequence, 1.0)), |_, _| ())
+    }
+
+    /// Train on each weighted sequence in turn, calling `on_error` with the index and error of each
+    /// sequence that is skipped.  Returns the number of sequences successfully trained and the
+    /// number that were skipped.
+    pub(crate) fn train_weighted_sequences<S>(&mut self, sequences: impl IntoIterator<Item = (S, f64)>, mut on_error: impl FnMut(usize, MultiMarkovError)) -> (usize, usize)
+        where S: IntoIterator<Item = T>
+    {
         let mut success_count: usize = 0;
         let mut error_count: usize = 0;
-        for sequence in sequences {
-            match self.train_sequence(sequence) {
-                Ok(()) => success_count+=1,
-                Err(_) => error_count+=1,
+        let mut interned = (self.interning && self.entropy_tracking.is_none()).then(InternedCounts::default);
+        for (index, (sequence, weight)) in sequences.into_iter().enumerate() {
+            let sequence = self.collect_sequence(sequence);
+            let remembered = self.training_sequences.as_ref().map(|_| sequence.clone());
+            let initial = sequence.first().cloned();
+            let sequence = self.add_sentinels(sequence);
+            match self.train_sequence(sequence, weight, interned.as_mut()) {
+                Ok(observations) => {
+                    success_count+=1;
+                    self.observations += observations;
+                    if let Some(initial) = initial {
+                        *self.initial_states.entry(initial).or_insert(0) += 1;
+                    }
+                    if let (Some(training_sequences), Some(sequence)) = (&mut self.training_sequences, remembered) {
+                        *training_sequences.entry(sequence).or_insert(0) += 1;
+                    }
+                },
+                Err(e) => {
+                    error_count+=1;
+                    on_error(index, e);
+                },
             };
         }
-        println!("{} sequences successfully trained; {} errors",success_count,error_count);
-        self
+        if let Some(interned) = interned {
+            self.add_interned(interned);
+        }
+        (success_count, error_count)
     }
 
-    /// Learn all the transitions possible from one training sequence, adding observations to the Markov model.
-    fn train_sequence(&mut self, sequence: Vec<T>) -> Result<(),&str> {
-        if sequence.len() < 2 { return Err("sequence was too short, must contain at least two states"); }
+    /// Add the counts of a batch trained with interning to `markov_chain`, translating their IDs
+    /// back into states.
+    fn add_interned(&mut self, interned: InternedCounts<T>) {
+        let states = interned.states;
+        for (context, transitions) in interned.counts {
+            let context: Vec<T> = context.iter().map(|&id| states[id as usize].clone()).collect();
+            let merged = self.markov_chain.entry(context).or_default();
+            for (next, weight) in transitions {
+                *merged.entry(states[next as usize].clone()).or_insert(0.0) += weight;
+            }
+        }
+    }
 
-        // loop backwards through the characters in the sequence
-        for i in (1..sequence.len()).rev() {
-            // Build a running set of all known characters while we're at it
-            self.known_states.insert(sequence[i].clone());
+    /// The positions `i` in a sequence of the given length whose states are recorded as following
+    /// something: every state after the leading start sentinels, or without them every state but
+    /// the first (or, in an order-0 model, every state).
+    fn follower_positions(&self, length: usize) -> Range<usize> {
+        let first = if self.start_sentinel.is_some() { self.start_padding() } else if self.order > 0 { 1 } else { 0 };
+        first.min(length)..length
+    }
 
-            // For the sequences preceding character (i), record that character (i) was observed following them.
-            // IE if the char_vec is ['R','U','S','T'] and this is a 3rd-order model, then for the three models ['S'], ['U','S'], and ['R','U','S'] we record that ['T'] is a known follower.
-            for j in (max(0,i as i32 - self.order) as usize)..i {
-                if let Some(transitions_from) = self.markov_chain.get_mut(&sequence[j..i]) {
-                    // "from" sequence has been seen before
-                    if let Some(weight) = transitions_from.get_mut(&sequence[i]) {
-                        // it has been seen before with this transition; add one observance
-                        *weight += 1.0;
-                    } else {
-                        // it hasn't been seen before with this transition; insert transition with one observance
-                        transitions_from.insert(sequence[i].clone(), 1.0);
+    /// How many copies of the start sentinel begin each training sequence.
+    fn start_padding(&self) -> usize {
+        self.order.max(1) as usize
+    }
+
+    /// The (context, following state) pairs recorded for a sequence: each of the states at
+    /// `follower_positions`, with each tail of up to `order` states preceding it (and the empty
+    /// context too, with a marginal fallback or in an order-0 model).
+    fn windows<'s, K>(&self, sequence: &'s [K]) -> impl Iterator<Item = (&'s [K], &'s K)> + 's {
+        let first = self.follower_positions(sequence.len()).start;
+        context_windows(sequence, self.order as usize, first, self.marginal_fallback || self.order == 0)
+    }
+
+    /// Collect a training sequence, reversing it if the builder trains on reversed sequences.
+    fn collect_sequence(&self, sequence: impl IntoIterator<Item = T>) -> Vec<T> {
+        let mut sequence: Vec<T> = sequence.into_iter().collect();
+        if self.reversed { sequence.reverse(); }
+        sequence
+    }
+
+    fn add_sentinels(&self, sequence: impl IntoIterator<Item = T>) -> Vec<T> {
+        match (&self.start_sentinel, &self.sentinel) {
+            (Some(start), Some(end)) => std::iter::repeat_n(start.clone(), self.start_padding())
+                .chain(sequence)
+                .chain(std::iter::once(end.clone()))
+                .collect(),
+            _ => sequence.into_iter().collect(),
+        }
+    }
+
+    /// Removes the influence of sequences that were previously trained, by subtracting the same
+    /// transition counts that `train` added for them.  Transitions whose count drops to zero are
+    /// removed, as are models left with no transitions, and states that no longer follow anything.
+    /// A state stops being an initial state once no remaining training sequence begins with it, and
+    /// a remembered sequence (see `with_remembered_inputs`) is forgotten once it has been untrained
+    /// as many times as it was trained.
+    ///
+    /// A sequence that can't be untrained because it was never trained (i.e. some of its
+    /// transitions don't have a large enough count) is skipped, leaving the model unchanged.  Any
+    /// other error, such as a sequence too short to have been trained, is returned at once (the
+    /// sequences before it have already been untrained).
+    pub fn untrain<S>(mut self, sequences: impl IntoIterator<Item = S>) -> Result<Self,MultiMarkovError>
+        where S: IntoIterator<Item = T>
+    {
+        for sequence in sequences {
+            let sequence = self.collect_sequence(sequence);
+            let remembered = self.training_sequences.as_ref().map(|_| sequence.clone());
+            let initial = sequence.first().cloned();
+            let sequence = self.add_sentinels(sequence);
+            match self.untrain_sequence(sequence) {
+                Ok(()) => {
+                    if let Some(initial) = initial {
+                        decrement(&mut self.initial_states, initial);
+                    }
+                    if let (Some(training_sequences), Some(sequence)) = (&mut self.training_sequences, remembered) {
+                        decrement(training_sequences, sequence);
                     }
-                } else {
-                    // "from" sequence hasn't been seen before; add it and add the observed transition
-                    let mut observed_transition = HashMap::new();
-                    observed_transition.insert(sequence[i].clone(), 1.0);
-                    self.markov_chain.insert(Vec::from(&sequence[j..i]), observed_transition);
+                },
+                Err(MultiMarkovError::UntrainedSequence) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self)
+    }
+
+    /// Subtract all the transitions observed in one sequence from the Markov model, exactly
+    /// inverting `train_sequence` with a weight of 1.0.
+    fn untrain_sequence(&mut self, sequence: Vec<T>) -> Result<(),MultiMarkovError> {
+        if sequence.len() == 1 && self.unigrams {
+            // the lone state was recorded under the empty, order-0 model
+            let unigrams = self.markov_chain.get_mut(&[][..]).ok_or(MultiMarkovError::UntrainedSequence)?;
+            let count = unigrams.get_mut(&sequence[0]).ok_or(MultiMarkovError::UntrainedSequence)?;
+            if *count < 1.0 { return Err(MultiMarkovError::UntrainedSequence); }
+            *count -= 1.0;
+            if *count <= 0.0 {
+                unigrams.remove(&sequence[0]);
+                if unigrams.is_empty() {
+                    self.markov_chain.remove(&[][..]);
                 }
-                // The following one-liner might accomplish all of the above, but is pretty hard on the eyes:
-                //     *self.markov_chain.entry(Vec::from(&sequence[j..i])).or_insert(HashMap::new()).entry(sequence[i].clone()).or_insert(0.0) += 1.0;
             }
+            if !self.markov_chain.values().any(|transitions| transitions.contains_key(&sequence[0])) {
+                self.known_states.remove(&sequence[0]);
+            }
+            return Ok(());
         }
+        if self.follower_positions(sequence.len()).is_empty() { return Err(MultiMarkovError::SequenceTooShort); }
 
+        // tally the decrements first, so that nothing is changed if the sequence was never trained
+        let mut decrements: HashMap<(&[T],&T),f64> = HashMap::new();
+        for window in self.windows(&sequence) {
+            *decrements.entry(window).or_insert(0.0) += 1.0;
+        }
+        for ((context, next), decrement) in decrements.iter() {
+            let count = self.markov_chain.get(*context).and_then(|transitions| transitions.get(*next));
+            if count.is_none_or(|count| count < decrement) {
+                return Err(MultiMarkovError::UntrainedSequence);
+            }
+        }
+
+        for ((context, next), decrement) in decrements {
+            let transitions = self.markov_chain.get_mut(context).unwrap();
+            let count = transitions.get_mut(next).unwrap();
+            *count -= decrement;
+            if *count <= 0.0 {
+                transitions.remove(next);
+                if transitions.is_empty() {
+                    self.markov_chain.remove(context);
+                }
+            }
+        }
+        for state in &sequence[self.follower_positions(sequence.len())] {
+            if !self.markov_chain.values().any(|transitions| transitions.contains_key(state)) {
+                self.known_states.remove(state);
+            }
+        }
         Ok(())
     }
 
-    /// Adds prior probabilities (if any) and builds the MultiMarkov object.
-    pub fn build(mut self) -> MultiMarkov<T> {
-        self.add_priors();
-        MultiMarkov {
-            markov_chain: self.markov_chain,
-            known_states: self.known_states,
-            order: self.order
+    /// Keeps only the `max_followers` heaviest transitions from each context, removing the rest
+    /// (ties are broken arbitrarily).  Unlike `prune`, which applies one threshold to the whole
+    /// model, this bounds the memory used by each context, e.g. on pathological data where some
+    /// contexts are followed by a huge variety of states.  Call it after training; since priors are
+    /// only added when the model is built, they are never removed by this, nor limited by it.
+    pub fn truncate_followers(mut self, max_followers: usize) -> Self {
+        for transitions in self.markov_chain.values_mut() {
+            if transitions.len() <= max_followers { continue; }
+            let mut ranked: Vec<(T,f64)> = transitions.drain().collect();
+            ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+            transitions.extend(ranked.into_iter().take(max_followers));
         }
+        self.markov_chain.retain(|_, transitions| !transitions.is_empty());
+        self
     }
 
-    /// Fills in missing state transitions with a given value so that any known state (except
-    /// those only seen at the end of sequences) can transition to any other known state.
-    /// Should be called after training is complete, because only then do we know the full set of
-    /// known states, and which transitions are unobserved.
-    fn add_priors(&mut self) {
-        match self.prior {
-            Some(p) => {
-                for v in self.markov_chain.values_mut() {
-                    for a in self.known_states.iter() {
-                        v.entry(a.clone()).or_insert(p);
-                    }
-                }
+    /// Multiplies every observed transition's weight by `factor`, so that older observations count
+    /// for less than ones trained afterwards.  Calling this periodically between batches of
+    /// training implements exponential forgetting, for a model that adapts to a stream of data.
+    /// Weights that fall below `DECAY_EPSILON` are removed, as by `prune`, so that the model
+    /// stays bounded.  Panics unless `factor` is greater than zero and less than one.
+    pub fn decay(mut self, factor: f64) -> Self {
+        assert!(factor>0.0 && factor<1.0,"Decay factor must be greater than zero and less than one.");
+        for weight in self.markov_chain.values_mut().flat_map(|transitions| transitions.values_mut()) {
+            *weight *= factor;
+        }
+        self.prune(Self::DECAY_EPSILON)
+    }
+
+    /// Removes every observed transition whose weight is below `min_count`, and any model left
+    /// with no transitions at all.  This shrinks a model trained on a large, noisy corpus while
+    /// keeping its dominant patterns.  Call it after training; priors and other smoothing are only
+    /// applied when the model is built, so they are never pruned.
+    pub fn prune(mut self, min_count: f64) -> Self {
+        self.markov_chain.retain(|_, transitions| {
+            transitions.retain(|_, weight| *weight >= min_count);
+            !transitions.is_empty()
+        });
+        self
+    }
+
+    /// Adds the observed transition counts and known states of another builder to this one, so
+    /// that models trained separately (e.g. on shards of a large corpus) can be combined.  The
+    /// result is the same as if this builder had been trained on both sets of training data.
+    ///
+    /// Returns an `Err` if the two builders have different orders.
+    pub fn merge(mut self, other: MultiMarkovBuilder<T>) -> Result<Self,MultiMarkovError> {
+        if self.order != other.order { return Err(MultiMarkovError::OrderMismatch(self.order, other.order)); }
+        for (context, transitions) in other.markov_chain {
+            let merged = self.markov_chain.entry(context).or_default();
+            for (next, weight) in transitions {
+                *merged.entry(next).or_insert(0.0) += weight;
+            }
+        }
+        self.known_states.extend(other.known_states);
+        for (state, count) in other.initial_states {
+            *self.initial_states.entry(state).or_insert(0) += count;
+        }
+        self.observations += other.observations;
+        if let (Some((log_loss, weight)), Some((other_log_loss, other_weight))) = (&mut self.entropy_tracking, other.entropy_tracking) {
+            *log_loss += other_log_loss;
+            *weight += other_weight;
+        }
+        if let (Some(training_sequences), Some(other_sequences)) = (&mut self.training_sequences, other.training_sequences) {
+            for (sequence, count) in other_sequences {
+                *training_sequences.entry(sequence).or_insert(0) += count;
+            }
+        }
+        Ok(self)
+    }
+
+    /// A builder with the same training settings as this one, but nothing trained yet.
+    #[cfg(feature = "rayon")]
+    fn untrained_copy(&self) -> Self {
+        let mut builder = MultiMarkovBuilder::<T>::new().with_order(self.order);
+        builder.start_sentinel = self.start_sentinel.clone();
+        builder.sentinel = self.sentinel.clone();
+        builder.unigrams = self.unigrams;
+        builder.marginal_fallback = self.marginal_fallback;
+        builder.reversed = self.reversed;
+        if self.training_sequences.is_some() {
+            builder.training_sequences = Some(StateMap::default());
+        }
+        builder
+    }
+
+    /// The total of `-ln p` over the sequence's transitions, under the model as trained so far (see
+    /// `with_entropy_tracking`).
+    fn prequential_log_loss(&self, sequence: &[T]) -> f64 {
+        let vocabulary = self.known_states.len() as f64 + 1.0;
+        self.follower_positions(sequence.len())
+            .map(|i| {
+                let longest = min(self.order as usize, i);
+                let seen = (0..=longest).rev()
+                    .find_map(|length| self.markov_chain.get(&sequence[(i-length)..i]));
+                let probability = match seen {
+                    Some(transitions) => {
+                        let count = transitions.get(&sequence[i]).copied().unwrap_or(0.0);
+                        (count + 1.0) / (transitions.values().sum::<f64>() + vocabulary)
+                    },
+                    None => 1.0 / vocabulary,
+                };
+                -probability.ln()
+            })
+            .sum()
+    }
+
+    /// Learn all the transitions possible from one training sequence, adding observations to the
+    /// Markov model.  Each observation adds `weight` to the transition's count (1.0 for ordinary training).
+    /// Returns the number of observations, i.e. of (context, following state) counts increased.
+    /// With `interned` counts, the observations are counted there instead of in `markov_chain`.
+    fn train_sequence(&mut self, sequence: Vec<T>, weight: f64, interned: Option<&mut InternedCounts<T>>) -> Result<usize,MultiMarkovError> {
+        if weight.is_nan() || weight <= 0.0 { return Err(MultiMarkovError::InvalidWeight(weight)); }
+        if sequence.len() == 1 && self.unigrams {
+            // record the lone state under the empty, order-0 model
+            self.known_states.insert(sequence[0].clone());
+            *self.markov_chain.entry(Vec::new()).or_default().entry(sequence[0].clone()).or_insert(0.0) += weight;
+            return Ok(1);
+        }
+        if self.follower_positions(sequence.len()).is_empty() { return Err(MultiMarkovError::SequenceTooShort); }
+
+        if self.entropy_tracking.is_some() {
+            let log_loss = self.prequential_log_loss(&sequence);
+            let transitions = self.follower_positions(sequence.len()).len() as f64;
+            if let Some((total_log_loss, total_weight)) = &mut self.entropy_tracking {
+                *total_log_loss += weight * log_loss;
+                *total_weight += weight * transitions;
+            }
+        }
+
+        // Build a running set of all known characters (cloning only new ones)
+        for state in &sequence[self.follower_positions(sequence.len())] {
+            if !self.known_states.contains(state) {
+                self.known_states.insert(state.clone());
+            }
+        }
+
+        // For the sequences preceding each character, record that the character was observed following them.
+        // IE if the char_vec is ['R','U','S','T'] and this is a 3rd-order model, then for the three models ['S'], ['U','S'], and ['R','U','S'] we record that ['T'] is a known follower.
+        // With a marginal fallback, we record ['T'] in the empty model [] as well.
+        let observations = match interned {
+            Some(interned) => {
+                let ids: Vec<u32> = sequence.iter().map(|state| interned.intern(state)).collect();
+                count_windows(&mut interned.counts, self.windows(&ids), weight)
+            },
+            None => {
+                let windows = self.windows(&sequence);
+                count_windows(&mut self.markov_chain, windows, weight)
+            },
+        };
+        Ok(observations)
+    }
+
+    /// Adds prior probabilities or other smoothing (if any) and builds the MultiMarkov object.
+    ///
+    /// This is the only place smoothing is applied, and it consumes the builder, so priors can't
+    /// be added twice, nor mistaken for observations by training the model further afterwards.
+    ///
+    /// Panics if the smoothing can't be applied to the trained counts; use `try_build` to get an
+    /// `Err` instead.
+    pub fn build(self) -> MultiMarkov<T> {
+        self.build_with_weights()
+    }
+
+    /// Like `build`, but returns an `Err` instead of panicking if the smoothing can't be applied
+    /// to the trained counts, such as an absolute discount that isn't smaller than every count,
+    /// or if Witten-Bell smoothing replaced a prior that was chosen explicitly.
+    pub fn try_build(self) -> Result<MultiMarkov<T>,MultiMarkovError> {
+        self.try_build_with_weights()
+    }
+
+    /// Like `build`, but the model stores its weights as `W`, e.g. `build_with_weights::<f32>()`
+    /// to halve the memory they take.  Training and smoothing still work in `f64`, and the
+    /// smoothed weights are converted as the model is built (see `Weight`).
+    pub fn build_with_weights<W: Weight>(self) -> MultiMarkov<T, W> {
+        self.try_build_with_weights().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `build_with_weights`, but returns an `Err` instead of panicking, as `try_build` does,
+    /// or if a weight is too large for `W`.
+    pub fn try_build_with_weights<W: Weight>(mut self) -> Result<MultiMarkov<T, W>,MultiMarkovError> {
+        if self.explicit_prior && self.witten_bell { return Err(MultiMarkovError::ConflictingSmoothing); }
+        let smoothing = self.smoothing.take();
+        match &smoothing {
+            Some(SmoothingMethod::PriorMap(priors)) => priors.smooth(&mut self.markov_chain, &self.known_states),
+            Some(SmoothingMethod::LazyPrior(_)) => (), // applied by the model when queried
+            Some(SmoothingMethod::Custom(strategy)) => {
+                strategy.validate(&self.markov_chain)?;
+                strategy.smooth(&mut self.markov_chain, &self.known_states);
             },
             None => (),
         }
+        debug_assert!(self.markov_chain.keys().all(|context| context.len() <= self.order as usize),
+            "The model has contexts longer than its order.");
+        let lazy_prior = match smoothing {
+            Some(SmoothingMethod::LazyPrior(p)) => Some(to_weight(p)?),
+            _ => None,
+        };
+        let markov_chain = self.markov_chain.into_iter()
+            .map(|(context, transitions)| {
+                let transitions = transitions.into_iter()
+                    .map(|(next, weight)| Ok((next, to_weight(weight)?)))
+                    .collect::<Result<_,MultiMarkovError>>()?;
+                Ok((context, transitions))
+            })
+            .collect::<Result<_,MultiMarkovError>>()?;
+        let mut model = MultiMarkov {
+            markov_chain,
+            known_states: self.known_states,
+            initial_states: self.initial_states.into_keys().collect(),
+            order: self.order,
+            max_context: self.order,
+            stable_order: self.stable_order,
+            strict_states: self.strict_states,
+            backoff_penalty: self.backoff_penalty,
+            rng: self.rng.unwrap_or_else(thread_seeded_rng),
+            start_sentinel: self.start_sentinel,
+            sentinel: self.sentinel,
+            max_length: self.max_length,
+            min_length: self.min_length,
+            repetition_penalty: self.repetition_penalty,
+            decode_mode: self.decode_mode,
+            training_sequences: self.training_sequences.map(|sequences| sequences.into_keys().collect()),
+            weight_sums: StateMap::default(),
+            stable_states: Vec::new(),
+            normalized: false,
+            lazy_prior,
+        };
+        model.refresh_weight_sums();
+        Ok(model)
+    }
+
+}
+
+#[cfg(feature = "rayon")]
+impl<T> MultiMarkovBuilder<T>
+    where T: Eq + Hash + Clone + Send + Sync
+{
+    /// Like `train`, but trains on the sequences in parallel using rayon.  Each thread counts
+    /// transitions into its own partial model, and the partial models are then combined with
+    /// `merge`, so the result is exactly the same as training on the sequences serially.
+    ///
+    /// Requires the `rayon` feature.
+    pub fn train_parallel<S>(self, sequences: impl IntoParallelIterator<Item = S>) -> Self
+        where S: IntoIterator<Item = T> + Send
+    {
+        let template = self.untrained_copy();
+        let partial_builder = || template.untrained_copy();
+        let partial = sequences.into_par_iter()
+            .fold(partial_builder, |mut builder, sequence| {
+                builder.train_sequences(std::iter::once(sequence));
+                builder
+            })
+            .reduce(partial_builder, |a, b| a.merge(b).expect("Partial models have the same order."));
+        self.merge(partial).expect("Partial models have the same order.")
+    }
+}
+
+impl MultiMarkovBuilder<char> {
+    /// Train a character model on a piece of text, treating the whole text as one sequence of
+    /// `char`s.
+    pub fn train_text(self, text: &str) -> Self {
+        self.train(std::iter::once(text.chars()))
+    }
+
+    /// Train a character model on every line read from `reader`, treating each line (without its
+    /// line ending) as one sequence, e.g. to train on a file containing a list of words.  Lines
+    /// that can't be trained, such as empty ones, are skipped.  Returns an error if reading fails.
+    ///
+    /// Lines are read one at a time, so a compressed corpus can be streamed through a decoder
+    /// instead of being decompressed to disk first; see `train_gzip_lines` for gzip.
+    ///
+    /// ```
+    /// use multimarkov::MultiMarkov;
+    /// let names = "foo\nbar\nbaz\n";
+    /// let mm = MultiMarkov::<char>::builder()
+    ///     .train_lines(names.as_bytes())
+    ///     .unwrap()
+    ///     .build();
+    /// assert!(mm.known_states.contains(&'z'));
+    /// ```
+    pub fn train_lines(mut self, reader: impl BufRead) -> Result<Self,MultiMarkovError> {
+        for line in reader.lines() {
+            self.train_sequences(std::iter::once(line?.chars()));
+        }
+        Ok(self)
     }
 
+    /// Like `train_lines`, but decompressing gzipped input (such as a `.gz` word list file) as
+    /// it is read, without decompressing it to disk first.  Returns `MultiMarkovError::Io` if
+    /// reading fails or the input isn't valid gzip, e.g. because it was cut short.
+    ///
+    /// Requires the `flate2` feature.
+    #[cfg(feature = "flate2")]
+    pub fn train_gzip_lines(self, reader: impl Read) -> Result<Self,MultiMarkovError> {
+        self.train_lines(BufReader::new(GzDecoder::new(reader)))
+    }
+}
 
+impl<T> Default for MultiMarkovBuilder<T>
+    where T: Eq + Hash + Clone
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// Every (context, following state) pair in a sequence, as a model of the given order records
+/// them in training: each state but the first, paired with each tail of up to `order` states
+/// preceding it, longest first.  In an order-0 model, every state is paired with the empty context
+/// instead.  Training uses the same windows, after adding the sentinel (if any), and adds the
+/// empty context with a marginal fallback.  Panics if `order` is negative.
+///
+/// ```
+/// use multimarkov::builder::contexts_of;
+/// let pairs: Vec<(&[char], &char)> = contexts_of(&['a','b','c'], 2).collect();
+/// assert_eq!(pairs, vec![(&['a'][..], &'b'), (&['a','b'][..], &'c'), (&['b'][..], &'c')]);
+/// ```
+pub fn contexts_of<T>(sequence: &[T], order: i32) -> impl Iterator<Item = (&[T], &T)> {
+    assert!(order>=0,"Order must be a non-negative integer.");
+    context_windows(sequence, order as usize, if order > 0 { 1 } else { 0 }, order == 0)
+}
+
+/// The (context, following state) pairs for each state from position `first` on: the state with
+/// each tail of up to `order` preceding states, and with the empty context too if `with_empty`.
+fn context_windows<T>(sequence: &[T], order: usize, first: usize, with_empty: bool) -> impl Iterator<Item = (&[T], &T)> {
+    (first.min(sequence.len())..sequence.len()).flat_map(move |i| {
+        let last_j = if with_empty { i+1 } else { i };
+        (i.saturating_sub(order)..last_j).map(move |j| (&sequence[j..i], &sequence[i]))
+    })
+}
+
+/// Add `weight` to the count of each (context, following state) pair in `counts`, returning the
+/// number of pairs counted.
+fn count_windows<'s, K>(counts: &mut StateMap<Vec<K>,StateMap<K,f64>>, windows: impl Iterator<Item = (&'s [K], &'s K)>, weight: f64) -> usize
+    where K: Eq + Hash + Clone + 's
+{
+    let mut observations = 0;
+    for (context, next) in windows {
+        observations += 1;
+        if let Some(transitions_from) = counts.get_mut(context) {
+            // "from" sequence has been seen before
+            if let Some(count) = transitions_from.get_mut(next) {
+                // it has been seen before with this transition; add one (weighted) observance
+                *count += weight;
+            } else {
+                // it hasn't been seen before with this transition; insert transition with one observance
+                transitions_from.insert(next.clone(), weight);
+            }
+        } else {
+            // "from" sequence hasn't been seen before; add it and add the observed transition
+            let mut observed_transition = StateMap::default();
+            observed_transition.insert(next.clone(), weight);
+            counts.insert(Vec::from(context), observed_transition);
+        }
+        // The following one-liner might accomplish all of the above, but is pretty hard on the eyes,
+        // and `entry` needs an owned key, so it would allocate a new Vec for every lookup:
+        //     *counts.entry(Vec::from(context)).or_insert(HashMap::new()).entry(next.clone()).or_insert(0.0) += weight;
+        // Looking up the slice first means we only allocate when a context is genuinely new.
+    }
+    observations
+}
+
+/// The counts of a batch of training with `MultiMarkovBuilder::with_interning`, with each state
+/// replaced by its ID: its index in `states`.
+struct InternedCounts<T> {
+    ids: StateMap<T,u32>,
+    states: Vec<T>,
+    counts: StateMap<Vec<u32>,StateMap<u32,f64>>,
+}
+
+impl<T> Default for InternedCounts<T> {
+    fn default() -> Self {
+        Self { ids: StateMap::default(), states: Vec::new(), counts: StateMap::default() }
+    }
+}
+
+impl<T> InternedCounts<T>
+    where T: Eq + Hash + Clone
+{
+    /// The state's ID, giving it the next one if it hasn't got one yet.
+    fn intern(&mut self, state: &T) -> u32 {
+        if let Some(&id) = self.ids.get(state) { return id; }
+        let id = u32::try_from(self.states.len()).expect("Too many distinct states to intern.");
+        self.ids.insert(state.clone(), id);
+        self.states.push(state.clone());
+        id
+    }
+}
+
+/// A weight converted to the model's weight type, or an `Err` if it's too large for it.
+fn to_weight<W: Weight>(weight: f64) -> Result<W,MultiMarkovError> {
+    let converted = W::from_f64(weight);
+    if weight.is_finite() && !converted.to_f64().is_finite() { return Err(MultiMarkovError::WeightOverflow(weight)); }
+    Ok(converted)
+}
+
+/// Count one fewer of `key`, removing it once none are left.
+fn decrement<K: Eq + Hash>(counts: &mut StateMap<K,usize>, key: K) {
+    if let Some(count) = counts.get_mut(&key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
 }
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashSet;
 
     fn char_data() -> Vec<Vec<char>> {
         vec![
@@ -163,17 +1151,66 @@ mod tests {
 
     #[test]
     fn test_can_train_char_sequences() {
-        let mm = MultiMarkov::<char>::builder().with_order(2).train(char_data().into_iter());
+        let _mm = MultiMarkov::<char>::builder().with_order(2).train(char_data());
     }
 
     #[test]
     fn test_can_train_string_sequences() {
-        let mm = MultiMarkov::<String>::builder().with_order(2).train(string_data().into_iter());
+        let _mm = MultiMarkov::<String>::builder().with_order(2).train(string_data());
+    }
+
+    #[test]
+    fn test_can_train_from_streamed_sequences() {
+        let words = "ace foobar baz";
+        let mm = MultiMarkov::<char>::builder().with_order(2).train(words.split(' ').map(|w| w.chars()));
+        assert!(mm.markov_chain.get(&*vec!['a','c']).unwrap().contains_key(&'e'));
+        assert_eq!(*mm.markov_chain.get(&*vec!['b']).unwrap().get(&'a').unwrap(),2.0);
+    }
+
+    #[test]
+    fn sentinels_are_trained_at_start_and_end() {
+        let mm = MultiMarkov::<char>::builder().with_order(2).with_sentinel('#').train(char_data());
+        // the single-state sequence ['a'] is now long enough to be trained
+        assert_eq!(*mm.markov_chain.get(&*vec!['#']).unwrap().get(&'a').unwrap(),2.0);
+        assert!(mm.markov_chain.get(&*vec!['#']).unwrap().contains_key(&'f'));
+        // states that previously ended a sequence are now followed by the sentinel
+        assert!(mm.markov_chain.get(&*vec!['a','z']).unwrap().contains_key(&'#'));
+        assert!(mm.known_states.contains(&'#'));
+    }
+
+    #[test]
+    fn start_sentinels_pad_contexts_to_full_length() {
+        let mm = MultiMarkov::<char>::builder().with_order(3).with_sentinel('#').train(vec![vec!['b','a','z']]);
+        assert!(mm.markov_chain.get(&*vec!['#','#','#']).unwrap().contains_key(&'b'));
+        assert!(mm.markov_chain.get(&*vec!['#','#','b']).unwrap().contains_key(&'a'));
+        assert!(mm.markov_chain.get(&*vec!['#','b','a']).unwrap().contains_key(&'z'));
+        // the padding itself isn't trained as following anything
+        assert!(!mm.markov_chain.get(&*vec!['#']).unwrap().contains_key(&'#'));
+        assert!(!mm.markov_chain.get(&*vec!['#','#']).unwrap().contains_key(&'#'));
+        assert_eq!(mm.markov_chain.get(&*vec!['a','z']).unwrap().get(&'#'), Some(&1.0));
+    }
+
+    #[test]
+    fn boundaries_use_separate_start_and_end_sentinels() {
+        let mut mm = MultiMarkov::<char>::builder().with_order(2).with_boundaries('^', '$').without_prior()
+            .train(char_data())
+            .build();
+        assert!(mm.markov_chain.get(&*vec!['^','^']).unwrap().contains_key(&'f'));
+        assert!(mm.markov_chain.get(&*vec!['a','z']).unwrap().contains_key(&'$'));
+        assert!(mm.is_known_state(&'$'));
+        assert!(!mm.is_known_state(&'^'));
+        for _ in 0..20 {
+            let generated = mm.generate_sequence(&[]);
+            assert!(!generated.is_empty());
+            assert!(!generated.contains(&'^') && !generated.contains(&'$'));
+        }
+        // 'a' begins two of the four sequences
+        assert!((mm.transition_probability(&['^','^'], &'a') - 0.5).abs() < 1e-12);
     }
 
     #[test]
     fn sequences_in_training_show_up_in_model() {
-        let mm = MultiMarkov::<char>::builder().with_order(2).train(char_data().into_iter());
+        let mm = MultiMarkov::<char>::builder().with_order(2).train(char_data());
         // 'e' comes after 'c' (end of 2nd sequence trained properly)
         assert!(mm.markov_chain.get(&*vec!['c']).unwrap().contains_key(&'e'));
         // 'a' -> 'c' (beginning of 2nd sequence trained properly)
@@ -189,11 +1226,176 @@ mod tests {
         assert!(!mm.markov_chain.get(&*vec!['a']).unwrap().contains_key(&'b'));
     }
 
+    #[test]
+    fn prune_removes_rare_transitions_and_empty_models() {
+        let mm = MultiMarkov::<char>::builder().with_order(2).train(char_data()).prune(2.0);
+        // 'b' -> 'a' is the only transition seen twice
+        assert_eq!(mm.markov_chain.len(), 1);
+        assert_eq!(*mm.markov_chain.get(&*vec!['b']).unwrap().get(&'a').unwrap(),2.0);
+        assert!(mm.known_states.contains(&'z'));
+        let mm = mm.build();
+        // priors were added after pruning
+        assert!(mm.markov_chain.get(&*vec!['b']).unwrap().contains_key(&'z'));
+    }
+
+    #[test]
+    fn merging_is_the_same_as_training_on_both() {
+        let data = char_data();
+        let (first, second) = data.split_at(2);
+        let merged = MultiMarkov::<char>::builder().with_order(2).train(first.to_vec())
+            .merge(MultiMarkov::<char>::builder().with_order(2).train(second.to_vec()))
+            .unwrap();
+        let combined = MultiMarkov::<char>::builder().with_order(2).train(char_data());
+        assert_eq!(merged.markov_chain, combined.markov_chain);
+        assert_eq!(merged.known_states, combined.known_states);
+    }
+
+    #[test]
+    fn cannot_merge_different_orders() {
+        let result = MultiMarkov::<char>::builder().with_order(2).train(char_data())
+            .merge(MultiMarkov::<char>::builder().with_order(3).train(char_data()));
+        assert!(matches!(result, Err(MultiMarkovError::OrderMismatch(2, 3))));
+    }
+
+    #[test]
+    fn skipped_sequences_are_reported() {
+        let mut skipped = Vec::new();
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .train_with_errors(char_data(), |index, error| skipped.push((index, error)))
+            .unwrap();
+        assert_eq!(skipped.len(), 1);
+        assert!(matches!(skipped[0], (0, MultiMarkovError::SequenceTooShort)));
+        assert_eq!(mm.markov_chain, MultiMarkov::<char>::builder().with_order(2).train(char_data()).markov_chain);
+        // nothing could be trained
+        let all_rejected = MultiMarkov::<char>::builder().train_with_errors(vec![vec!['a'], vec![]], |_, _| ());
+        assert!(matches!(all_rejected, Err(MultiMarkovError::EmptyInput)));
+        assert!(matches!(MultiMarkov::<char>::builder().train_with_errors(Vec::<Vec<char>>::new(), |_, _| ()), Err(MultiMarkovError::EmptyInput)));
+    }
+
+    #[test]
+    fn untraining_inverts_training() {
+        let data = char_data();
+        let trained = MultiMarkov::<char>::builder().with_order(2).with_sentinel('#').train(data[..3].to_vec());
+        let untrained = MultiMarkov::<char>::builder().with_order(2).with_sentinel('#').train(data.clone()).untrain(data[3..].to_vec()).unwrap();
+        assert_eq!(untrained.markov_chain, trained.markov_chain);
+        assert_eq!(untrained.known_states, trained.known_states);
+        // ['a'] was too short to train, so it can't be untrained either
+        let everything = MultiMarkov::<char>::builder().with_order(2).train(data.clone());
+        assert!(matches!(everything.untrain(data.clone()), Err(MultiMarkovError::SequenceTooShort)));
+        let everything_untrained = MultiMarkov::<char>::builder().with_order(2).train(data.clone()).untrain(data[1..].to_vec()).unwrap();
+        assert!(everything_untrained.markov_chain.is_empty());
+        assert!(everything_untrained.known_states.is_empty());
+    }
+
+    #[test]
+    fn untraining_an_unseen_sequence_changes_nothing() {
+        let trained = MultiMarkov::<char>::builder().with_order(2).train(char_data());
+        let untrained = MultiMarkov::<char>::builder().with_order(2).train(char_data())
+            .untrain(vec![vec!['b','a','r','f']]).unwrap(); // 'b','a','r' was trained, but 'r' -> 'f' never was
+        assert_eq!(untrained.markov_chain, trained.markov_chain);
+    }
+
+    #[test]
+    fn untraining_forgets_initial_states_no_longer_used() {
+        let mm = MultiMarkov::<char>::builder().with_order(1)
+            .train(vec![vec!['b','a','z'], vec!['b','o'], vec!['f','o','o']])
+            .untrain(vec![vec!['b','a','z'], vec!['f','o','o']])
+            .unwrap()
+            .build();
+        // 'b' still begins "bo", but nothing begins with 'f' any more
+        assert_eq!(mm.non_initial_states(), HashSet::from(['o']));
+    }
+
+    #[test]
+    fn untraining_forgets_remembered_sequences() {
+        let mm = MultiMarkov::<char>::builder().with_order(1).with_remembered_inputs()
+            .train(vec![vec!['b','a','z'], vec!['b','a','z'], vec!['b','o']])
+            .untrain(vec![vec!['b','a','z'], vec!['b','o']])
+            .unwrap()
+            .build();
+        assert_eq!(mm.is_training_sequence(&['b','a','z']), Some(true)); // it was trained twice
+        assert_eq!(mm.is_training_sequence(&['b','o']), Some(false));
+    }
+
+    #[test]
+    fn single_states_can_train_unigrams() {
+        let mm = MultiMarkov::<char>::builder().with_order(2).with_unigrams()
+            .train(vec![vec!['a'], vec!['a'], vec!['q'], vec!['b','a','z']]);
+        let unigrams = mm.markov_chain.get(&*Vec::<char>::new()).unwrap();
+        assert_eq!(*unigrams.get(&'a').unwrap(), 2.0);
+        assert_eq!(*unigrams.get(&'q').unwrap(), 1.0);
+        assert!(mm.known_states.contains(&'q'));
+        // without the option, single states are skipped as before
+        let mm = MultiMarkov::<char>::builder().with_order(2).train(vec![vec!['a'], vec!['b','a','z']]);
+        assert!(!mm.markov_chain.contains_key(&*Vec::<char>::new()));
+    }
+
+    #[test]
+    fn single_states_can_be_untrained_from_unigrams() {
+        let mm = MultiMarkov::<char>::builder().with_order(2).with_unigrams()
+            .train(vec![vec!['a'], vec!['a'], vec!['q'], vec!['b','a','z']])
+            .untrain(vec![vec!['a'], vec!['q']])
+            .unwrap();
+        let unigrams = mm.markov_chain.get(&*Vec::<char>::new()).unwrap();
+        assert_eq!(unigrams.get(&'a'), Some(&1.0));
+        assert_eq!(unigrams.get(&'q'), None);
+        assert!(!mm.known_states.contains(&'q'));
+        let mm = mm.untrain(vec![vec!['a'], vec!['a']]).unwrap(); // the second was never trained
+        assert!(!mm.markov_chain.contains_key(&*Vec::<char>::new()));
+        assert!(mm.known_states.contains(&'a')); // it still follows 'b'
+    }
+
+    #[test]
+    fn marginal_fallback_counts_every_state() {
+        let mm = MultiMarkov::<char>::builder().with_order(2).with_marginal_fallback().train(char_data());
+        let marginal = mm.markov_chain.get(&*Vec::<char>::new()).unwrap();
+        assert_eq!(*marginal.get(&'a').unwrap(), 2.0);
+        assert_eq!(*marginal.get(&'o').unwrap(), 2.0);
+        assert_eq!(marginal.values().sum::<f64>(), 9.0); // one per transition
+        let untrained = mm.untrain(char_data()[1..].to_vec()).unwrap();
+        assert!(untrained.markov_chain.is_empty());
+    }
+
+    #[test]
+    fn weighted_sequences_add_their_weight() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .train_weighted(vec![(vec!['b','a','z'], 2.5), (vec!['b','a','r'], 1.0)]);
+        assert_eq!(*mm.markov_chain.get(&*vec!['b']).unwrap().get(&'a').unwrap(),3.5);
+        assert_eq!(*mm.markov_chain.get(&*vec!['b','a']).unwrap().get(&'z').unwrap(),2.5);
+        // a weight of 1.0 is the same as ordinary training
+        let unweighted = MultiMarkov::<char>::builder().with_order(2).train(char_data());
+        let weighted = MultiMarkov::<char>::builder().with_order(2).train_weighted(char_data().into_iter().map(|s| (s, 1.0)));
+        assert_eq!(weighted.markov_chain, unweighted.markov_chain);
+    }
+
+    #[test]
+    fn non_positive_weights_are_skipped() {
+        let mut mm = MultiMarkov::<char>::builder().with_order(2);
+        let (success_count, error_count) = mm.train_weighted_sequences(vec![(vec!['b','a','z'], 0.0), (vec!['b','a','r'], -1.0)], |_, _| ());
+        assert_eq!((success_count, error_count), (0, 2));
+        assert!(mm.markov_chain.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn parallel_training_matches_serial_training() {
+        let data: Vec<Vec<char>> = char_data().into_iter().cycle().take(400).collect();
+        let parallel = MultiMarkov::<char>::builder().with_order(2).with_sentinel('#').with_marginal_fallback()
+            .with_remembered_inputs().train_parallel(data.clone());
+        let serial = MultiMarkov::<char>::builder().with_order(2).with_sentinel('#').with_marginal_fallback()
+            .with_remembered_inputs().train(data);
+        assert_eq!(parallel.markov_chain, serial.markov_chain);
+        assert_eq!(parallel.known_states, serial.known_states);
+        assert_eq!(parallel.training_sequences, serial.training_sequences);
+    }
+
     #[test]
     fn can_set_priors_and_they_work() {
         let mm = MultiMarkov::<char>::builder()
             .with_order(2)
-            .train(char_data().into_iter())
+            .train(char_data())
             .with_prior(0.015)
             .build();
         // prior should be set for a non-observed transition such as 'a' -> 'b'
@@ -205,7 +1407,7 @@ mod tests {
     fn make_sure_it_works_with_strings_too() {
         let mm = MultiMarkov::<String>::builder()
             .with_order(2)
-            .train(string_data().into_iter())
+            .train(string_data())
             .with_prior(0.011)
             .build();
         // prior should be set for a non-observed transition such as 'a' -> 'b'
@@ -217,7 +1419,7 @@ mod tests {
     fn can_specify_no_priors_and_build() {
         let mm = MultiMarkov::<char>::builder()
             .with_order(2)
-            .train(char_data().into_iter())
+            .train(char_data())
             .without_prior()
             .build();
         // a non-observed transition such as 'a' -> 'b' should have no entry in the model
@@ -225,10 +1427,343 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected="Order must be an integer greater than zero.")]
-    fn order_cannot_be_zero_or_negative() {
-        let mm = MultiMarkov::<char>::builder().with_order(0).train(char_data().into_iter());
+    fn kneser_ney_smoothing_produces_distributions() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .train(char_data())
+            .with_kneser_ney(0.75)
+            .build();
+        for transitions in mm.markov_chain.values() {
+            assert_eq!(transitions.len(), mm.known_states.len());
+            assert!((transitions.values().sum::<f64>() - 1.0).abs() < 1e-9);
+        }
+        // 'b' -> 'a' was observed; 'b' -> 'e' was not, but still gets some weight
+        let from_b = mm.markov_chain.get(&*vec!['b']).unwrap();
+        assert!(*from_b.get(&'e').unwrap() > 0.0);
+        assert!(*from_b.get(&'a').unwrap() > *from_b.get(&'e').unwrap());
     }
 
+    #[test]
+    fn last_smoothing_choice_wins() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .train(char_data())
+            .with_kneser_ney(0.75)
+            .with_prior(0.015)
+            .build();
+        assert_eq!(*mm.markov_chain.get(&*vec!['a']).unwrap().get(&'b').unwrap(),0.015);
+        assert_eq!(*mm.markov_chain.get(&*vec!['b']).unwrap().get(&'a').unwrap(),2.0);
+    }
+
+    #[test]
+    #[should_panic(expected="order must be a non-negative integer")]
+    fn order_cannot_be_negative() {
+        let _mm = MultiMarkov::<char>::builder().with_order(-1).train(char_data());
+    }
+
+    #[test]
+    fn try_with_order_returns_err_for_negative() {
+        assert!(MultiMarkov::<char>::builder().try_with_order(0).is_ok());
+        assert!(MultiMarkov::<char>::builder().try_with_order(-1).is_err());
+        assert!(MultiMarkov::<char>::builder().try_with_order(-3).is_err());
+        assert!(MultiMarkov::<char>::builder().try_with_order(5).is_ok());
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_train_from_text_and_lines() {
+        let from_text = MultiMarkovBuilder::new()
+            .with_order(2)
+            .train_text("foobar")
+            .train_text("baz");
+        let from_lines = MultiMarkovBuilder::new()
+            .with_order(2)
+            .train_lines("foobar\r\n\nbaz".as_bytes())
+            .unwrap();
+        assert_eq!(from_text.markov_chain, from_lines.markov_chain);
+        assert_eq!(from_text.known_states, from_lines.known_states);
+        assert_eq!(from_text.markov_chain.get(&vec!['o','o']).unwrap().get(&'b'), Some(&1.0));
+    }
+
+    #[cfg(feature = "flate2")]
+    fn gzip(text: &str) -> Vec<u8> {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "flate2")]
+    fn test_train_gzip_lines() {
+        let from_gzip = MultiMarkovBuilder::new()
+            .with_order(2)
+            .train_gzip_lines(&gzip("foobar\nbaz\n")[..])
+            .unwrap();
+        let from_lines = MultiMarkovBuilder::new()
+            .with_order(2)
+            .train_lines("foobar\nbaz\n".as_bytes())
+            .unwrap();
+        assert_eq!(from_gzip.markov_chain, from_lines.markov_chain);
+        assert_eq!(from_gzip.known_states, from_lines.known_states);
+    }
+
+    #[test]
+    #[cfg(feature = "flate2")]
+    fn test_train_gzip_lines_rejects_truncated_input() {
+        let compressed = gzip("foobar\nbaz\nqux\n");
+        let truncated = &compressed[..compressed.len() - 10];
+        let result = MultiMarkovBuilder::new().train_gzip_lines(truncated);
+        assert!(matches!(result, Err(MultiMarkovError::Io(_))));
+    }
+
+    #[test]
+    fn test_from_counts() {
+        let trained = MultiMarkovBuilder::new().with_order(2).train(char_data());
+        let imported = MultiMarkovBuilder::from_counts(2, trained.markov_chain.clone()).unwrap();
+        assert_eq!(imported.known_states, trained.known_states);
+        assert_eq!(imported.build().markov_chain, trained.build().markov_chain);
+        let too_long: StateMap<Vec<char>,StateMap<char,f64>> = vec![(vec!['a','b','c'], vec![('d', 1.0)].into_iter().collect())].into_iter().collect();
+        assert!(matches!(MultiMarkovBuilder::from_counts(2, too_long), Err(MultiMarkovError::ContextTooLong(3, 2))));
+        let negative: StateMap<Vec<char>,StateMap<char,f64>> = vec![(vec!['a'], vec![('b', -1.0)].into_iter().collect())].into_iter().collect();
+        assert!(matches!(MultiMarkovBuilder::from_counts(2, negative), Err(MultiMarkovError::InvalidWeight(_))));
+    }
+
+    #[test]
+    fn test_reserve_is_only_a_hint() {
+        let builder = MultiMarkovBuilder::new().with_order(2).reserve(1000, 100);
+        assert!(builder.markov_chain.capacity() >= 1000);
+        assert!(builder.known_states.capacity() >= 100);
+        let reserved = builder.train(char_data());
+        let unreserved = MultiMarkovBuilder::new().with_order(2).train(char_data());
+        assert_eq!(reserved.markov_chain, unreserved.markov_chain);
+        assert_eq!(reserved.known_states, unreserved.known_states);
+    }
+
+    #[test]
+    fn test_contexts_of_matches_training() {
+        let sequence = ['f','o','o','b','a','r'];
+        let pairs: Vec<(&[char], &char)> = contexts_of(&sequence, 2).collect();
+        assert_eq!(pairs.len(), MultiMarkovBuilder::new().with_order(2).train(vec![sequence]).observation_count());
+        assert_eq!(pairs[0], (&['f'][..], &'o'));
+        assert_eq!(pairs[pairs.len()-1], (&['a'][..], &'r'));
+        let unigram_pairs: Vec<(&[char], &char)> = contexts_of(&sequence, 0).collect();
+        assert_eq!(unigram_pairs.len(), 6);
+        assert!(unigram_pairs.iter().all(|(context, _)| context.is_empty()));
+    }
+
+    #[test]
+    fn test_observation_count() {
+        let builder = MultiMarkovBuilder::new().with_order(2).train(char_data());
+        // ['a'] is skipped; "ace" and "baz" record 1+2 each, and "foobar" records 1+2+2+2+2
+        assert_eq!(builder.observation_count(), 15);
+        let other = MultiMarkovBuilder::new().with_order(2).train(vec![vec!['a','b']]);
+        assert_eq!(builder.merge(other).unwrap().observation_count(), 16);
+    }
+
+    #[test]
+    fn test_entropy_rate_tracking() {
+        assert_eq!(MultiMarkovBuilder::new().with_order(1).train(vec![vec!['a','b']]).current_entropy_rate(), None);
+        let builder = MultiMarkovBuilder::new().with_order(1).with_entropy_tracking();
+        assert_eq!(builder.current_entropy_rate(), None);
+        // the first 'a' -> 'b' is scored before anything is known; the second with 'b' the only
+        // known state, seen once after ['a']: (1 + 1) / (1 + 1 + 1)
+        let builder = builder.train(vec![vec!['a','b'], vec!['a','b']]);
+        assert!((builder.current_entropy_rate().unwrap() - 1.5f64.ln() / 2.0).abs() < 1e-12);
+        // the rate falls as the same data keeps being seen
+        let rate = builder.current_entropy_rate().unwrap();
+        let builder = builder.train(vec![vec!['a','b']; 50]);
+        assert!(builder.current_entropy_rate().unwrap() < rate / 2.0);
+    }
+
+    #[test]
+    fn test_absolute_discounting() {
+        let model = MultiMarkovBuilder::new()
+            .with_order(2)
+            .with_absolute_discounting(0.5)
+            .train(char_data())
+            .build();
+        let after_ba = model.markov_chain.get(&vec!['b','a']).unwrap();
+        // 'r' and 'z' give up 0.5 each, shared between the other five known states
+        assert_eq!(after_ba.get(&'r'), Some(&0.5));
+        assert_eq!(after_ba.get(&'z'), Some(&0.5));
+        assert_eq!(after_ba.get(&'o'), Some(&0.2));
+        assert_eq!(after_ba.len(), model.known_states.len());
+        assert!((after_ba.values().sum::<f64>() - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    #[should_panic(expected="discount 1 must be smaller than the smallest observed count")]
+    fn test_absolute_discount_must_be_smaller_than_counts() {
+        MultiMarkovBuilder::new()
+            .with_absolute_discounting(1.0)
+            .train(char_data())
+            .build();
+    }
+
+    #[test]
+    fn test_witten_bell_conflicts_with_a_prior() {
+        let built = MultiMarkovBuilder::new().with_prior(0.1).with_witten_bell().train(char_data()).try_build();
+        assert!(matches!(built, Err(MultiMarkovError::ConflictingSmoothing)));
+        // replacing the default prior is fine
+        assert!(MultiMarkovBuilder::new().with_witten_bell().train(char_data()).try_build().is_ok());
+    }
+
+    #[test]
+    fn test_interning_trains_the_same_counts() {
+        let words = |text: &str| -> Vec<String> { text.split(' ').map(String::from).collect() };
+        let batches = [
+            vec![words("the cat sat on the mat"), words("the dog sat"), words("a")],
+            vec![words("the cat ran"), words("the mat")],
+        ];
+        let builder = || MultiMarkov::<String>::builder()
+            .with_order(2)
+            .with_boundaries(String::from("^"), String::from("$"))
+            .with_marginal_fallback()
+            .with_unigrams();
+        let plain = batches.iter().fold(builder(), |builder, batch| builder.train(batch.clone()));
+        let interned = batches.iter().fold(builder().with_interning(), |builder, batch| builder.train(batch.clone()));
+        assert_eq!(interned.markov_chain, plain.markov_chain);
+        assert_eq!(interned.known_states, plain.known_states);
+        assert_eq!(interned.observation_count(), plain.observation_count());
+        let weighted = builder().with_interning().train_weighted(vec![(words("the cat sat"), 2.0), (words("the cat"), 0.5)]);
+        assert_eq!(weighted.markov_chain[&words("the cat")][&String::from("sat")], 2.0);
+        assert_eq!(weighted.markov_chain[&words("the")][&String::from("cat")], 2.5);
+    }
+
+    #[test]
+    fn test_build_with_weights_rejects_overflowing_counts() {
+        let counts: StateMap<Vec<char>,StateMap<char,f64>> = vec![(vec!['a'], vec![('b', 1e39)].into_iter().collect())].into_iter().collect();
+        let builder = || MultiMarkovBuilder::from_counts(1, counts.clone()).unwrap().without_prior();
+        assert!(matches!(builder().try_build_with_weights::<f32>(), Err(MultiMarkovError::WeightOverflow(weight)) if weight == 1e39));
+        assert_eq!(builder().build().markov_chain[&vec!['a']][&'b'], 1e39);
+    }
+
+    #[test]
+    fn test_witten_bell_can_be_replaced() {
+        // smoothing chosen afterwards replaces Witten-Bell, so it no longer conflicts with a prior
+        let built = MultiMarkovBuilder::new().with_witten_bell().with_kneser_ney(0.75).with_prior(0.1).train(char_data()).try_build();
+        assert!(built.is_ok());
+        let built = MultiMarkovBuilder::new().with_witten_bell().with_lazy_prior(0.1).train(char_data()).try_build();
+        assert!(built.is_ok());
+        // nor does a prior that something else replaced before Witten-Bell
+        let built = MultiMarkovBuilder::new().with_prior(0.1).with_absolute_discounting(0.5).with_witten_bell().train(char_data()).try_build();
+        assert!(built.is_ok());
+    }
+
+    #[test]
+    fn test_try_build_rejects_a_bad_discount() {
+        let built = MultiMarkovBuilder::new()
+            .with_absolute_discounting(1.5)
+            .train(char_data())
+            .try_build();
+        assert!(matches!(built, Err(MultiMarkovError::InvalidDiscount(discount)) if discount == 1.5));
+        let built = MultiMarkovBuilder::new()
+            .with_absolute_discounting(0.5)
+            .train(char_data())
+            .try_build();
+        assert!(built.is_ok());
+    }
+
+    #[test]
+    fn test_decay_forgets_old_observations() {
+        let builder = MultiMarkovBuilder::new()
+            .with_order(1)
+            .train(char_data())
+            .decay(0.5)
+            .train(vec![vec!['b','o']]);
+        let after_b = builder.markov_chain.get(&vec!['b']).unwrap();
+        assert_eq!(after_b.get(&'a'), Some(&1.0)); // seen twice, then halved
+        assert_eq!(after_b.get(&'o'), Some(&1.0));
+        let mut builder = builder;
+        for _ in 0..25 {
+            builder = builder.decay(0.5);
+        }
+        assert!(builder.markov_chain.is_empty());
+    }
+
+    #[test]
+    fn test_reversed_sequences_predict_predecessors() {
+        let builder = MultiMarkovBuilder::new()
+            .with_order(2)
+            .with_reversed_sequences()
+            .train(char_data());
+        // 'o' is preceded by 'f' and 'o' in "foobar"
+        let before_o = builder.markov_chain.get(&vec!['o']).unwrap();
+        assert_eq!(before_o.get(&'f'), Some(&1.0));
+        assert_eq!(before_o.get(&'o'), Some(&1.0));
+        assert_eq!(builder.markov_chain.get(&vec!['z','a']).unwrap().get(&'b'), Some(&1.0));
+        let untrained = builder.untrain(vec![vec!['b','a','z']]).unwrap();
+        assert!(!untrained.markov_chain.contains_key(&vec!['z','a']));
+    }
+
+    #[test]
+    fn test_order_cannot_change_after_training() {
+        let builder = MultiMarkovBuilder::new().with_order(2).train(char_data());
+        assert!(matches!(builder.try_with_order(3), Err(MultiMarkovError::OrderChangedAfterTraining)));
+        let builder = MultiMarkovBuilder::new().with_order(2).train(char_data());
+        assert_eq!(builder.try_with_order(2).unwrap().build().order(), 2);
+    }
+
+    #[test]
+    fn test_lazy_prior_matches_eager_prior() {
+        let eager = MultiMarkovBuilder::new().with_order(2).with_prior(0.01).train(char_data()).build();
+        let lazy = MultiMarkovBuilder::new().with_order(2).with_lazy_prior(0.01).train(char_data()).build();
+        assert!(lazy.stats().total_weight < eager.stats().total_weight);
+        assert_eq!(lazy.markov_chain.get(&vec!['b','a']).unwrap().len(), 2);
+        for context in [&['b','a'][..], &['o'], &['x','c']] {
+            let eager_distribution = eager.next_distribution(context).unwrap();
+            let lazy_distribution = lazy.next_distribution(context).unwrap();
+            assert_eq!(eager_distribution.len(), lazy_distribution.len());
+            for (state, probability) in eager_distribution {
+                assert!((lazy_distribution[&state] - probability).abs() < 1e-12);
+                assert!((lazy.transition_probability(context, &state) - probability).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_prior_map() {
+        let priors: HashMap<char,f64> = [('z', 0.0001), ('e', 0.0)].into_iter().collect();
+        let model = MultiMarkovBuilder::new()
+            .with_order(2)
+            .with_prior_map(priors, 0.01)
+            .train(char_data())
+            .build();
+        let after_ba = model.markov_chain.get(&vec!['b','a']).unwrap();
+        assert_eq!(after_ba.get(&'r'), Some(&1.0)); // observed counts are untouched
+        assert_eq!(after_ba.get(&'o'), Some(&0.01));
+        assert!(!after_ba.contains_key(&'e'));
+        let after_f = model.markov_chain.get(&vec!['f']).unwrap();
+        assert_eq!(after_f.get(&'z'), Some(&0.0001));
+    }
+
+    #[test]
+    fn test_order_zero_counts_overall_frequencies() {
+        let builder = MultiMarkovBuilder::new()
+            .with_order(0)
+            .without_prior()
+            .train(char_data());
+        assert_eq!(builder.markov_chain.len(), 1);
+        let frequencies = builder.markov_chain.get(&Vec::new()).unwrap();
+        assert_eq!(frequencies.get(&'a'), Some(&4.0)); // including the lone 'a'
+        assert_eq!(frequencies.get(&'f'), Some(&1.0));
+        let mut mm = builder.untrain(vec![vec!['a']]).unwrap().build();
+        assert_eq!(mm.markov_chain[&Vec::new()].get(&'a'), Some(&3.0));
+        assert!(mm.random_next(&['x','y']).is_some());
+    }
+
+    #[test]
+    fn test_truncate_followers() {
+        let builder = MultiMarkovBuilder::new()
+            .with_order(1)
+            .train(char_data())
+            .train(vec![vec!['a','r']])
+            .truncate_followers(1);
+        assert!(builder.markov_chain.values().all(|transitions| transitions.len() == 1));
+        // 'r' followed 'a' twice, more often than 'c' or 'z'
+        assert_eq!(builder.markov_chain.get(&vec!['a']).unwrap().get(&'r'), Some(&2.0));
+        let model = builder.build();
+        assert_eq!(model.markov_chain.get(&vec!['a']).unwrap().len(), model.known_states.len());
+    }
+
+}
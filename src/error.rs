@@ -0,0 +1,93 @@
+use std::error::Error;
+use std::fmt;
+
+/// The errors that can be returned by this crate.
+#[derive(Debug)]
+pub enum MultiMarkovError {
+    /// No training sequences were provided, or none of them could be trained.
+    EmptyInput,
+    /// A training sequence had fewer than two states (and so no transitions).
+    SequenceTooShort,
+    /// The requested order was negative.
+    InvalidOrder(i32),
+    /// The order can't be changed once training has begun.
+    OrderChangedAfterTraining,
+    /// A training sequence's weight was zero, negative or `NaN`.
+    InvalidWeight(f64),
+    /// A sequence being untrained had never been trained.
+    UntrainedSequence,
+    /// A sequence being scored contained a state never seen in training.
+    UnknownState,
+    /// A context (in imported counts, or a lookup limit) was longer than the model's order.
+    ContextTooLong(usize, i32),
+    /// Two models with different orders can't be combined.
+    OrderMismatch(i32, i32),
+    /// A smoothing discount was not smaller than the smallest observed count, which would leave
+    /// transitions with no weight or negative weight.
+    InvalidDiscount(f64),
+    /// Witten-Bell smoothing replaced a prior that was chosen explicitly, but it needs the raw counts.
+    ConflictingSmoothing,
+    /// A weight was too large for the model's weight type (see `Weight`).
+    WeightOverflow(f64),
+    /// A file could not be read or written.
+    Io(std::io::Error),
+    /// A model could not be serialized or deserialized.
+    #[cfg(feature = "bincode")]
+    Serialization(bincode::Error),
+    /// A file being loaded is not a saved model.
+    NotAModelFile,
+    /// A file being loaded was saved in a different, incompatible format version.
+    UnsupportedFormatVersion(u32),
+    /// A file being loaded was saved from a model with weights of this many bytes, unlike the
+    /// weight type it's being loaded as.
+    WeightTypeMismatch(usize),
+}
+
+impl fmt::Display for MultiMarkovError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MultiMarkovError::EmptyInput => write!(f, "no training sequences were provided, or none could be trained"),
+            MultiMarkovError::SequenceTooShort => write!(f, "sequence was too short, must contain at least two states"),
+            MultiMarkovError::InvalidOrder(_) => write!(f, "order must be a non-negative integer"),
+            MultiMarkovError::OrderChangedAfterTraining => write!(f, "order cannot be changed after training has begun"),
+            MultiMarkovError::InvalidWeight(weight) => write!(f, "sequence weight must be greater than zero, but was {}", weight),
+            MultiMarkovError::UntrainedSequence => write!(f, "sequence was never trained"),
+            MultiMarkovError::UnknownState => write!(f, "sequence contains a state never seen in training"),
+            MultiMarkovError::ContextTooLong(length, order) => write!(f, "context of length {} is longer than the model's order ({})", length, order),
+            MultiMarkovError::OrderMismatch(a, b) => write!(f, "cannot combine models of different orders ({} and {})", a, b),
+            MultiMarkovError::InvalidDiscount(discount) => write!(f, "discount {} must be smaller than the smallest observed count", discount),
+            MultiMarkovError::ConflictingSmoothing => write!(f, "a prior can't be combined with Witten-Bell smoothing"),
+            MultiMarkovError::WeightOverflow(weight) => write!(f, "weight {} is too large for the model's weight type", weight),
+            MultiMarkovError::Io(e) => write!(f, "I/O error: {}", e),
+            #[cfg(feature = "bincode")]
+            MultiMarkovError::Serialization(e) => write!(f, "serialization error: {}", e),
+            MultiMarkovError::NotAModelFile => write!(f, "file is not a saved MultiMarkov model"),
+            MultiMarkovError::UnsupportedFormatVersion(version) => write!(f, "unsupported model format version {}", version),
+            MultiMarkovError::WeightTypeMismatch(size) => write!(f, "model was saved with {}-byte weights, a different weight type", size),
+        }
+    }
+}
+
+impl Error for MultiMarkovError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MultiMarkovError::Io(e) => Some(e),
+            #[cfg(feature = "bincode")]
+            MultiMarkovError::Serialization(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for MultiMarkovError {
+    fn from(e: std::io::Error) -> Self {
+        MultiMarkovError::Io(e)
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl From<bincode::Error> for MultiMarkovError {
+    fn from(e: bincode::Error) -> Self {
+        MultiMarkovError::Serialization(e)
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::iter::FusedIterator;
+use rand::Rng;
+use rand::rngs::StdRng;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use crate::{MultiMarkov, Weight, max_weight, tempered};
+
+/// How a `Generator` picks each state from the possible following states.  Set it for a model
+/// with `MultiMarkovBuilder::with_decode_mode`, or for one generator with
+/// `Generator::with_decode_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum DecodeMode {
+    /// Always pick the most likely state, as `MultiMarkov::most_likely_next` does.
+    Greedy,
+    /// Draw states in proportion to their weights, as `MultiMarkov::random_next` does.
+    #[default]
+    Sample,
+    /// Draw with this temperature, as `MultiMarkov::random_next_with_temperature` does.
+    Temperature(f64),
+    /// Draw from the nucleus reaching this probability, as `MultiMarkov::random_next_top_p` does.
+    TopP(f64),
+}
+
+impl DecodeMode {
+    /// Panics if the temperature or `p` is out of range.
+    pub(crate) fn validate(&self) {
+        match self {
+            DecodeMode::Temperature(temperature) => assert!(*temperature>0.0,"Temperature must be greater than zero."),
+            DecodeMode::TopP(p) => assert!(*p > 0.0 && *p <= 1.0, "p must be greater than zero and at most 1."),
+            _ => (),
+        }
+    }
+}
+
+/// An iterator that lazily generates a sequence of states from a `MultiMarkov` model, drawing one
+/// state per call to `next()` with its own random number generator.  Create one with
+/// `MultiMarkov::generator`, which seeds it from the model's generator, or with
+/// `MultiMarkov::generator_with` to supply one.  The model is only borrowed immutably, so several
+/// generators can run from one model at once.
+///
+/// The iterator ends when the model's end sentinel is drawn (the sentinel itself is not yielded), or
+/// when no model matches the sequence generated so far.  Otherwise it runs forever: a model
+/// trained without a sentinel and with priors will never stop, so use adaptors like `take` to
+/// limit the output.
+///
+/// If the model was built with a repetition penalty (see
+/// `MultiMarkovBuilder::with_repetition_penalty`), states that appeared recently are drawn less often.
+/// If it was built with a minimum length (see `MultiMarkovBuilder::with_min_length`), states that
+/// would end the sequence at once aren't drawn until `start` and the generated states reach that length.
+/// Note that `take` and the like can still stop it sooner.
+///
+/// States are drawn according to the model's `DecodeMode`, unless overridden with `with_decode_mode`.
+/// A greedy generator always makes the same choices, so without a sentinel it may loop forever.
+pub struct Generator<'a, T, R = StdRng, W = f64>
+    where T: Eq + Hash + Clone, R: Rng, W: Weight
+{
+    model: &'a MultiMarkov<T, W>,
+    rng: R,
+    context: Vec<T>,
+    recent: VecDeque<T>,
+    length: usize,
+    min_length: usize,
+    mode: DecodeMode,
+    finished: bool,
+}
+
+impl<'a, T, R, W> Generator<'a, T, R, W>
+    where T: Eq + Hash + Clone, R: Rng, W: Weight
+{
+    /// Start generating after the given sequence, as if the model's start sentinels (if any)
+    /// preceded it.
+    pub(crate) fn new(model: &'a MultiMarkov<T, W>, start: &[T], rng: R) -> Self {
+        let context = model.start_context(start);
+        let window = model.repetition_penalty.map_or(0, |(_, window)| window);
+        let recent = start[start.len().saturating_sub(window)..].iter().cloned().collect();
+        Self {
+            mode: model.decode_mode,
+            min_length: model.min_length,
+            model,
+            rng,
+            context,
+            recent,
+            length: start.len(),
+            finished: false,
+        }
+    }
+
+    /// Pick states with this decoding mode instead of the model's.  Panics if the mode's
+    /// parameter is out of range.
+    pub fn with_decode_mode(mut self, mode: DecodeMode) -> Self {
+        mode.validate();
+        self.mode = mode;
+        self
+    }
+
+    /// Keep going until `start` and the generated states reach this length, instead of the
+    /// model's minimum length.
+    pub(crate) fn with_min_length(mut self, min_length: usize) -> Self {
+        self.min_length = min_length;
+        self
+    }
+}
+
+impl<T, R, W> Iterator for Generator<'_, T, R, W>
+    where T: Eq + Hash + Clone, R: Rng, W: Weight
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.finished { return None; }
+        let below_minimum = self.length < self.min_length;
+        // the state that reaches the minimum may itself be the last
+        let last_needed = self.length + 1 == self.min_length;
+        let drawn = if self.mode == DecodeMode::Sample && self.model.repetition_penalty.is_none() && !below_minimum {
+            self.model.random_next_with(&self.context, &mut self.rng)
+        } else {
+            let r: f64 = if self.mode == DecodeMode::Greedy { 0.0 } else { self.rng.gen() };
+            let model = self.model;
+            let recent = &self.recent;
+            let context = &self.context;
+            let temperature = match self.mode {
+                DecodeMode::Temperature(temperature) => temperature,
+                _ => 1.0,
+            };
+            let max_weight = match model.best_model(context) {
+                Some(bestmodel) if temperature != 1.0 => max_weight(&bestmodel),
+                _ => W::from_f64(1.0),
+            };
+            let nucleus = match self.mode {
+                DecodeMode::TopP(p) => Some(model.nucleus(context, p)),
+                _ => None,
+            };
+            let weight = |k: &T, weight: W| {
+                if below_minimum && Some(k) == model.sentinel.as_ref() { return W::ZERO; }
+                if below_minimum && !last_needed && model.ends_generation(context, k) { return W::ZERO; }
+                if nucleus.as_ref().is_some_and(|nucleus| !nucleus.contains(k)) { return W::ZERO; }
+                let weight = if temperature == 1.0 { weight } else { tempered(weight, max_weight, temperature) };
+                match model.repetition_penalty {
+                    Some((penalty, _)) => {
+                        let repeats = recent.iter().filter(|&state| state == k).count();
+                        weight / W::from_f64(penalty.powi(repeats as i32))
+                    },
+                    None => weight,
+                }
+            };
+            match self.mode {
+                DecodeMode::Greedy => model.most_likely_weighted(context, weight),
+                _ => model.draw_weighted(context, r, weight),
+            }
+        };
+        match drawn {
+            Some(next) if Some(&next) != self.model.sentinel.as_ref() => {
+                self.length += 1;
+                self.context.push(next.clone());
+                // only the last `order` states can affect the next draw
+                if self.context.len() > self.model.order as usize {
+                    self.context.remove(0);
+                }
+                if let Some((_, window)) = self.model.repetition_penalty {
+                    self.recent.push_back(next.clone());
+                    if self.recent.len() > window {
+                        self.recent.pop_front();
+                    }
+                }
+                Some(next)
+            },
+            _ => {
+                self.finished = true;
+                None
+            },
+        }
+    }
+}
+
+impl<T, R, W> FusedIterator for Generator<'_, T, R, W>
+    where T: Eq + Hash + Clone, R: Rng, W: Weight
+{}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn char_data() -> Vec<Vec<char>> {
+        vec![
+            vec!['a','c','e'],
+            vec!['f','o','o','b','a','r'],
+            vec!['b','a','z'],
+        ]
+    }
+
+    #[test]
+    fn test_generator_stops_at_sentinel() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .with_sentinel('#')
+            .with_seed(3)
+            .train(char_data())
+            .build();
+        for _ in 0..100 {
+            let generated: Vec<char> = mm.generator(&[]).collect();
+            assert!(!generated.is_empty());
+            assert!(!generated.contains(&'#'));
+        }
+        // only 'a' and 'e' can follow ['a','c'], and 'e' always ends the sequence
+        assert_eq!(mm.generator(&['a','c']).collect::<Vec<char>>(), vec!['e']);
+    }
+
+    #[test]
+    fn test_generator_can_run_forever() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_marginal_fallback()
+            .train(char_data())
+            .build();
+        assert_eq!(mm.generator(&['f']).take(1000).count(), 1000);
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .train(char_data())
+            .build();
+        let mut generator = mm.generator(&['z']);
+        assert_eq!(generator.next(), None); // 'z' has no following states
+        assert_eq!(generator.next(), None);
+    }
+
+    #[test]
+    fn test_generators_with_own_rngs_share_a_model() {
+        use rand::SeedableRng;
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_sentinel('#')
+            .train(char_data())
+            .build();
+        for seed in 0..10 {
+            // both run from the same immutable borrow at once
+            let first = mm.generator_with(&['b'], StdRng::seed_from_u64(seed));
+            let second = mm.generator_with(&['b'], StdRng::seed_from_u64(seed));
+            assert_eq!(first.collect::<Vec<char>>(), second.collect::<Vec<char>>());
+        }
+    }
+
+    #[test]
+    fn test_repetition_penalty_discourages_loops() {
+        // without a penalty, "banana" style alternation between 'a' and 'n' dominates the output
+        let data = vec![vec!['b','a','n','a','n','a','b']];
+        let loop_fraction = |penalty: f64| {
+            let mut mm = MultiMarkov::<char>::builder()
+                .with_order(1)
+                .without_prior()
+                .with_repetition_penalty(penalty, 2)
+                .with_seed(5)
+                .train(data.clone())
+                .build();
+            let generated: Vec<char> = mm.generator(&['b']).take(2000).collect();
+            assert_eq!(generated.len(), 2000);
+            let loops = generated.windows(3).filter(|w| w == &['n','a','n']).count();
+            loops as f64 / 2000.0
+        };
+        assert!(loop_fraction(100.0) < loop_fraction(1.0) / 4.0);
+    }
+
+    #[test]
+    fn test_decode_modes() {
+        // 'a' is followed by 'n' twice and by 'b' once; 'n' and 'b' are only followed by 'a'
+        let data = vec![vec!['b','a','n','a','n','a','b']];
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .without_prior()
+            .with_decode_mode(DecodeMode::Greedy)
+            .train(data.clone())
+            .build();
+        assert_eq!(mm.generate_sequence(&['b']).len(), MultiMarkov::<char>::DEFAULT_MAX_LENGTH);
+        assert_eq!(mm.generator(&['b']).take(5).collect::<String>(), "anana");
+        // only 'n' is in the 0.6 nucleus after 'a', so the output is the same
+        assert_eq!(mm.generator(&['b']).with_decode_mode(DecodeMode::TopP(0.6)).take(5).collect::<String>(), "anana");
+        let sampled: String = mm.generator(&['b']).with_decode_mode(DecodeMode::Sample).take(200).collect();
+        assert!(sampled.contains("ab"));
+        assert_eq!(MultiMarkov::<char>::builder().train(data).build().decode_mode, DecodeMode::Sample);
+    }
+
+    #[test]
+    fn test_min_length_avoids_ending_early() {
+        let data = vec![vec!['a','b'], vec!['a','c','a','c','a','b'], vec!['x','y']];
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .without_prior()
+            .with_sentinel('#')
+            .with_min_length(6)
+            .train(data)
+            .build();
+        for _ in 0..100 {
+            assert!(mm.generator(&['a']).count() >= 5);
+        }
+        // only 'y' ever followed 'x', and nothing but the sentinel followed 'y', so generation gives up
+        assert_eq!(mm.generate_sequence(&['x']), vec!['x']);
+    }
+
+    #[test]
+    fn test_generate_exact() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .without_prior()
+            .with_sentinel('#')
+            .with_max_length(3)
+            .with_repetition_penalty(2.0, 2)
+            .train(vec![vec!['a','b'], vec!['a','c','a','c','a','b'], vec!['x','y']])
+            .build();
+        for _ in 0..100 {
+            let code = mm.generate_exact(8, &['a']).unwrap();
+            assert_eq!(code.len(), 8);
+            assert!(!code.contains(&'#'));
+        }
+        // 'y' may be the last state, but nothing can follow it
+        assert_eq!(mm.generate_exact(2, &['x']), Some(vec!['x','y']));
+        assert_eq!(mm.generate_exact(3, &['x']), None);
+        assert_eq!(mm.generate_exact(1, &['x','y']), None);
+    }
+
+}
@@ -0,0 +1,95 @@
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "fast-hash")]
+use std::hash::{BuildHasherDefault, Hasher};
+
+/// The hash map type used for the model's transitions, known states and caches (in both
+/// `MultiMarkov` and `MultiMarkovBuilder`): a `std::collections::HashMap` with the standard
+/// library's hasher, or with the `fast-hash` feature, with a faster one.  Write the types of
+/// these maps with this alias (e.g. in a `Smoothing` implementation), and the code compiles
+/// with the feature on or off.  Create one with `StateMap::default()`.
+pub type StateMap<K,V> = HashMap<K,V,StateHasher>;
+
+/// The hash set type used for the model's known states: see `StateMap`.
+pub type StateSet<T> = HashSet<T,StateHasher>;
+
+#[cfg(not(feature = "fast-hash"))]
+type StateHasher = std::collections::hash_map::RandomState;
+
+#[cfg(feature = "fast-hash")]
+type StateHasher = BuildHasherDefault<FastHasher>;
+
+/// A fast, non-cryptographic hasher (the "Fx" hash used by the Rust compiler) for the short keys
+/// the model hashes on every lookup.  Unlike the standard library's hasher it isn't seeded
+/// randomly, so it gives no protection against inputs crafted to collide; only enable it for
+/// training data you trust.  It isn't nameable outside the crate, so that it can be changed.
+#[cfg(feature = "fast-hash")]
+#[derive(Default)]
+pub struct FastHasher(u64);
+
+#[cfg(feature = "fast-hash")]
+impl FastHasher {
+    const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+    fn add(&mut self, word: u64) {
+        self.0 = (self.0.rotate_left(5) ^ word).wrapping_mul(Self::SEED);
+    }
+}
+
+#[cfg(feature = "fast-hash")]
+impl Hasher for FastHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            self.add(u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        let mut rest = [0u8; 8];
+        let remainder = chunks.remainder();
+        if !remainder.is_empty() {
+            rest[..remainder.len()].copy_from_slice(remainder);
+            self.add(u64::from_le_bytes(rest));
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.add(i as u64);
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.add(i as u64);
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.add(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.add(i);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.add(i as u64);
+    }
+}
+
+
+#[cfg(test)]
+#[cfg(feature = "fast-hash")]
+mod tests {
+    use super::*;
+    use std::hash::BuildHasher;
+
+    #[test]
+    fn test_fast_hasher_tells_short_keys_apart() {
+        let hasher = StateHasher::default();
+        let hashes: HashSet<u64> = ["", "a", "b", "ab", "ba", "aaaaaaaab", "aaaaaaaac"].iter()
+            .map(|key| hasher.hash_one(key))
+            .collect();
+        assert_eq!(hashes.len(), 7);
+        // unlike the standard hasher, it hashes the same way every time
+        assert_eq!(hasher.hash_one(['a','b']), StateHasher::default().hash_one(['a','b']));
+    }
+}
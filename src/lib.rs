@@ -1,10 +1,30 @@
 pub mod builder;
+pub mod error;
+pub mod generator;
+mod hash;
+#[cfg(feature = "bincode")]
+mod persistence;
+pub mod sampler;
+pub mod smoothing;
+mod weight;
 
+use std::borrow::Cow;
 use std::cmp::min;
 use std::collections::{HashMap, HashSet};
-use std::hash::Hash;
+use std::fmt::{Display, Write};
+use std::hash::{Hash, Hasher};
 use rand::Rng;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use crate::builder::MultiMarkovBuilder;
+use crate::error::MultiMarkovError;
+use crate::generator::{DecodeMode, Generator};
+use crate::sampler::AliasSampler;
+
+pub use crate::hash::{StateMap, StateSet};
+pub use crate::weight::Weight;
 
 /// Multi-order Markov chain models with a Katz back-off, for procedural generation applications.
 ///
@@ -48,17 +68,84 @@ use crate::builder::MultiMarkovBuilder;
 /// let mm = MultiMarkov::<char>::builder()
 ///     .with_order(2) // omit to use default of 3
 ///     .with_prior(0.01) // omit to use default of 0.005, or call .without_prior() to disable priors
+///     .with_seed(42) // omit to seed the random number generator from `rand::thread_rng()`
 ///     .train(input_vec.into_iter())
 ///     .build();
 /// ```
 ///
 /// Use method `random_next` (see below) to use it to generate new sequences.
-pub struct MultiMarkov<T>
-    where T: Eq + Hash + Clone
+///
+/// A built model is `Send + Sync` whenever `T` is.  To generate from several threads at once,
+/// share the model in an `Arc` and give each thread its own random number generator for
+/// `random_next_with`, which only borrows the model immutably:
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use rand::SeedableRng;
+/// use rand::rngs::StdRng;
+/// use multimarkov::MultiMarkov;
+/// let mm = Arc::new(MultiMarkov::<char>::builder()
+///     .train(vec![vec!['f','o','o','b','a','r']])
+///     .build());
+/// let handles: Vec<_> = (0..4).map(|i| {
+///     let mm = Arc::clone(&mm);
+///     thread::spawn(move || {
+///         let mut rng = StdRng::seed_from_u64(i);
+///         mm.random_next_with(&['o'], &mut rng)
+///     })
+/// }).collect();
+/// for handle in handles {
+///     assert!(handle.join().unwrap().is_some());
+/// }
+/// ```
+///
+/// With the `serde` feature enabled, a trained model can be serialized and deserialized.  The
+/// random number generator is not serialized; a deserialized model's generator is seeded from
+/// `rand::thread_rng()`.  Nor are the cached weight sums (see `refresh_weight_sums`), which a
+/// deserialized model works out afresh on each draw until `refresh_weight_sums` is called;
+/// `load_from_file` calls it for you.
+///
+/// Models can be cloned, e.g. to experiment with variations of one trained model.  A clone's
+/// random number generator starts in the same state as the original's, so reseed it if the two
+/// shouldn't make the same draws.
+///
+/// The weights are stored as `f64` by default.  To halve the memory they take in a large model,
+/// store them as `f32` instead, with `MultiMarkovBuilder::build_with_weights::<f32>()`: see `Weight`
+/// for the range of counts an `f32` holds.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MultiMarkov<T, W = f64>
+    where T: Eq + Hash + Clone, W: Weight
 {
-    pub markov_chain: HashMap<Vec<T>,HashMap<T,f64>>,
-    pub known_states: HashSet<T>,
-    pub order: i32,
+    /// The trained model: for every sequence of up to `order` states seen in the training data,
+    /// the weights of the states seen following it.
+    ///
+    /// `random_next` uses cached sums of each context's weights; if you modify this directly, call
+    /// `refresh_weight_sums` afterwards.
+    pub markov_chain: StateMap<Vec<T>,StateMap<T,W>>,
+    pub known_states: StateSet<T>,
+    initial_states: StateSet<T>,
+    order: i32,
+    max_context: i32,
+    stable_order: bool,
+    strict_states: bool,
+    backoff_penalty: f64,
+    #[cfg_attr(feature = "serde", serde(skip, default = "thread_seeded_rng"))]
+    rng: StdRng,
+    start_sentinel: Option<T>,
+    sentinel: Option<T>, // the end sentinel
+    max_length: usize,
+    min_length: usize,
+    repetition_penalty: Option<(f64, usize)>,
+    decode_mode: DecodeMode,
+    training_sequences: Option<StateSet<Vec<T>>>,
+    #[cfg_attr(feature = "serde", serde(skip, default = "StateMap::default"))]
+    weight_sums: StateMap<Vec<T>,WeightSum<T,W>>,
+    #[cfg_attr(feature = "serde", serde(skip, default = "Vec::new"))]
+    stable_states: Vec<T>, // the known states in stable order, if the model keeps one
+    normalized: bool,
+    lazy_prior: Option<W>,
 }
 
 impl<T> MultiMarkov<T>
@@ -66,88 +153,2654 @@ impl<T> MultiMarkov<T>
 {
     pub const DEFAULT_ORDER: i32 = 3;
     pub const DEFAULT_PRIOR: f64 = 0.005;
+    pub const DEFAULT_MAX_LENGTH: usize = 100;
+    pub const DEFAULT_BACKOFF_ALPHA: f64 = 0.4;
 
     /// Create a builder to set up and train a MultiMarkov instance.
     pub fn builder() -> MultiMarkovBuilder<T> {
         MultiMarkovBuilder::<T>::new()
     }
 
-    /// Using the random-number generator and the "weights" of the various state transitions from
-    /// the trained model, draw a new state to follow the given sequence.
-    pub fn random_next(&self, current_sequence: &Vec<T>) -> Option<T> {
-        let bestmodel = self.best_model(current_sequence)?;
-        let sum_of_weights: f64 = bestmodel.values().sum();
-        // TODO: use an RNG or RNG seed stored in the struct, so the user can specify it if desired
-        let mut rng = rand::thread_rng();
+    /// Build and train a MultiMarkov instance in a single call.  This is shorthand for using the
+    /// builder: if `order` is `None` the default order is used, and if `prior` is `None` no prior
+    /// probabilities are added to the model.
+    ///
+    /// Returns an `Err` if `order` is negative, or if no sequence could be trained (including if
+    /// `sequences` is empty).
+    ///
+    /// ```
+    /// use multimarkov::MultiMarkov;
+    /// let input_vec = vec![
+    ///     vec!['f','o','o','b','a','r'],
+    ///     vec!['b','a','z'],
+    /// ];
+    /// let mm = MultiMarkov::<char>::train(input_vec.into_iter(), Some(2), Some(0.01)).unwrap();
+    /// assert_eq!(mm.order(), 2);
+    /// ```
+    pub fn train<S>(sequences: impl IntoIterator<Item = S>, order: Option<i32>, prior: Option<f64>) -> Result<Self,MultiMarkovError>
+        where S: IntoIterator<Item = T>
+    {
+        let mut builder = Self::builder();
+        if let Some(order) = order {
+            builder = builder.try_with_order(order)?;
+        }
+        builder = match prior {
+            Some(prior) => builder.with_prior(prior),
+            None => builder.without_prior(),
+        };
+        let (success_count, _) = builder.train_sequences(sequences);
+        if success_count == 0 { return Err(MultiMarkovError::EmptyInput); }
+        Ok(builder.build())
+    }
+}
+
+impl<T, W> MultiMarkov<T, W>
+    where T: Eq + Hash + Clone, W: Weight
+{
+    /// The "order" the model was built with, i.e. the length of the longest sequences for which it
+    /// has learned a model of following states.  It cannot be changed once the model is built.
+    pub fn order(&self) -> i32 {
+        self.order
+    }
+
+    /// Look up at most the last `max_context` states of a sequence, as if the model had been
+    /// trained with that order: `random_next`, generation, scoring and the other queries that back
+    /// off to the best-fitted model then start their search from a shorter tail.  This is a quick way
+    /// to try a lower order without retraining.  Set it back to `order()` to restore the default.
+    ///
+    /// Returns an `Err` if `max_context` is negative or greater than the model's order.
+    pub fn set_max_context(&mut self, max_context: i32) -> Result<(),MultiMarkovError> {
+        if max_context < 0 { return Err(MultiMarkovError::InvalidOrder(max_context)); }
+        if max_context > self.order { return Err(MultiMarkovError::ContextTooLong(max_context as usize, self.order)); }
+        self.max_context = max_context;
+        Ok(())
+    }
+
+    /// The longest tail of a sequence that lookups consider (see `set_max_context`).  The default
+    /// is the model's order.
+    pub fn max_context(&self) -> i32 {
+        self.max_context
+    }
+
+    /// Remove all learned transitions and known states, keeping the model's order, random number
+    /// generator and other settings, and the memory already allocated for its collections.
+    /// Afterwards, no model will be found for any sequence.  A model that remembers its training
+    /// sequences forgets them but keeps remembering (so `is_training_sequence` answers `false`).
+    pub fn clear(&mut self) {
+        self.markov_chain.clear();
+        self.known_states.clear();
+        self.initial_states.clear();
+        if let Some(training_sequences) = &mut self.training_sequences {
+            training_sequences.clear();
+        }
+        self.weight_sums.clear();
+        self.stable_states.clear();
+    }
+
+    /// Recompute the cached sum of the weights following each context, which `random_next` uses
+    /// to avoid summing them on every draw.  The sums are computed when the model is built; call
+    /// this after modifying `markov_chain` directly, or after deserializing a model.  A context
+    /// with no cached sum, or whose number of transitions has changed since it was cached, is
+    /// summed afresh on each draw instead, but a changed weight can't be detected.
+    ///
+    /// If the model keeps a stable order (see `MultiMarkovBuilder::with_stable_order`), this is
+    /// also when each context's states are sorted into that order, and summed in it.
+    pub fn refresh_weight_sums(&mut self) {
+        let stable_order = self.stable_order;
+        self.weight_sums = self.markov_chain.iter()
+            .map(|(context, transitions)| {
+                let (sum, order) = if stable_order {
+                    let sorted = sorted_transitions(transitions);
+                    (sorted.iter().map(|(_,v)| v).sum(), sorted.into_iter().map(|(k,_)| k.clone()).collect())
+                } else {
+                    (transitions.values().sum(), Vec::new())
+                };
+                (context.clone(), WeightSum { sum, len: transitions.len(), stable_order: order })
+            })
+            .collect();
+        self.stable_states = if stable_order {
+            let mut states: Vec<T> = self.known_states.iter().cloned().collect();
+            states.sort_by_key(stable_hash);
+            states
+        } else {
+            Vec::new()
+        };
+    }
+
+    /// The sum of a context's weights: the cached sum if there is one and it still counts the same
+    /// transitions, or else summed now.
+    fn weight_sum(&self, context: &[T], transitions: &StateMap<T,W>) -> W {
+        match self.weight_sums.get(context) {
+            Some(cached) if cached.len == transitions.len() => cached.sum,
+            _ if self.stable_order => sorted_transitions(transitions).iter().map(|(_,v)| v).sum(),
+            _ => transitions.values().sum(),
+        }
+    }
+
+    /// Convert every context's weights into probabilities that sum to 1, in place.  Sampling and
+    /// the probability methods are unaffected, since they only depend on relative weights, but
+    /// the raw counts are lost.  A lazily-applied prior (see `MultiMarkovBuilder::with_lazy_prior`)
+    /// is stored in the model first.  A built model can't be trained further, so normalized weights can
+    /// never be mixed with new counts.
+    pub fn normalize(&mut self) {
+        if let Some(prior) = self.lazy_prior.take() {
+            // the prior has to be stored to keep its weight relative to the normalized counts
+            for transitions in self.markov_chain.values_mut() {
+                for state in self.known_states.iter() {
+                    transitions.entry(state.clone()).or_insert(prior);
+                }
+            }
+        }
+        for transitions in self.markov_chain.values_mut() {
+            let sum_of_weights: W = transitions.values().sum();
+            for weight in transitions.values_mut() {
+                *weight /= sum_of_weights;
+            }
+        }
+        self.refresh_weight_sums();
+        self.normalized = true;
+    }
+
+    /// Whether `normalize` has been called, i.e. whether `markov_chain` holds probabilities
+    /// rather than counts.
+    pub fn is_normalized(&self) -> bool {
+        self.normalized
+    }
+
+    /// Blend this model with another at the level of probabilities, e.g. to adapt a general model
+    /// toward a niche vocabulary without retraining.  For every context known to either model, the
+    /// blended distribution is `alpha` times this model's normalized distribution plus
+    /// `1 - alpha` times the other's, where each model's distribution is the one `next_distribution`
+    /// would return for that context, so a model that never saw a context contributes the
+    /// distribution it would back off to.  If only one model has a distribution, it is used alone.
+    ///
+    /// The blended model is normalized, and takes its other settings and a copy of its random
+    /// number generator from this model.  Returns an error if the models' orders differ, and
+    /// panics if `alpha` is not between 0 and 1.
+    pub fn blend(&self, other: &Self, alpha: f64) -> Result<Self,MultiMarkovError> {
+        assert!((0.0..=1.0).contains(&alpha), "Alpha must be between 0 and 1.");
+        if self.order != other.order { return Err(MultiMarkovError::OrderMismatch(self.order, other.order)); }
+        let contexts: HashSet<&Vec<T>> = self.markov_chain.keys().chain(other.markov_chain.keys()).collect();
+        let markov_chain = contexts.into_iter().filter_map(|context| {
+            let blended = match (self.next_distribution(context), other.next_distribution(context)) {
+                (Some(mut ours), Some(theirs)) => {
+                    ours.values_mut().for_each(|p| *p *= alpha);
+                    for (next, q) in theirs {
+                        *ours.entry(next).or_insert(0.0) += (1.0 - alpha) * q;
+                    }
+                    ours
+                },
+                (Some(distribution), None) | (None, Some(distribution)) => distribution,
+                (None, None) => return None,
+            };
+            Some((context.clone(), blended.into_iter().map(|(next, p)| (next, W::from_f64(p))).collect()))
+        }).collect();
+        let training_sequences = match (&self.training_sequences, &other.training_sequences) {
+            (Some(ours), Some(theirs)) => Some(ours.union(theirs).cloned().collect()),
+            _ => None,
+        };
+        let mut blended = MultiMarkov {
+            markov_chain,
+            known_states: self.known_states.union(&other.known_states).cloned().collect(),
+            initial_states: self.initial_states.union(&other.initial_states).cloned().collect(),
+            order: self.order,
+            max_context: self.max_context,
+            stable_order: self.stable_order,
+            strict_states: self.strict_states,
+            backoff_penalty: self.backoff_penalty,
+            rng: self.rng.clone(),
+            start_sentinel: self.start_sentinel.clone(),
+            sentinel: self.sentinel.clone(),
+            max_length: self.max_length,
+            min_length: self.min_length,
+            repetition_penalty: self.repetition_penalty,
+            decode_mode: self.decode_mode,
+            training_sequences,
+            weight_sums: StateMap::default(),
+            stable_states: Vec::new(),
+            normalized: true,
+            lazy_prior: None,
+        };
+        blended.refresh_weight_sums();
+        Ok(blended)
+    }
+
+    /// The Kullback-Leibler divergence of the other model from this one, summed over the contexts
+    /// both models have learned, e.g. to detect drift between a reference model and a freshly
+    /// trained one.  For each shared context, this is the sum over following states of
+    /// `p * ln(p / q)`, where `p` and `q` are this model's and the other model's normalized
+    /// probabilities.  It is zero for identical models, and grows as they differ.
+    ///
+    /// If the other model gives zero probability to a transition this model has seen, the
+    /// divergence is infinite; build the other model with priors to avoid that.
+    pub fn kl_divergence(&self, other: &Self) -> f64 {
+        self.kl_divergence_by_context(other).values().sum()
+    }
+
+    /// Like `kl_divergence`, but return the divergence for each shared context separately.
+    pub fn kl_divergence_by_context(&self, other: &Self) -> HashMap<Vec<T>,f64> {
+        self.markov_chain.iter()
+            .filter_map(|(context, transitions)| {
+                let theirs = other.markov_chain.get(context)?;
+                let transitions = self.apply_lazy_prior(transitions);
+                let theirs = other.apply_lazy_prior(theirs);
+                let total = transitions.values().sum::<W>().to_f64();
+                let other_total = theirs.values().sum::<W>().to_f64();
+                let divergence = transitions.iter()
+                    .filter(|(_, weight)| **weight > W::ZERO)
+                    .map(|(next, weight)| {
+                        let p = weight.to_f64() / total;
+                        let q = theirs.get(next).map_or(0.0, |weight| weight.to_f64()) / other_total;
+                        p * (p / q).ln()
+                    })
+                    .sum();
+                Some((context.clone(), divergence))
+            })
+            .collect()
+    }
+
+    /// The total weight of the transitions recorded for exactly this context (not the best-fitted
+    /// model `random_next` would back off to), i.e. how many times it was observed followed by
+    /// something, for judging how far to trust its distribution.  Priors stored in the model are
+    /// included in the total; a lazy prior is not.  Returns 0.0 if there is no model for the context.
+    pub fn context_count(&self, context: &[T]) -> f64 {
+        self.markov_chain.get(context).map_or(0.0, |transitions| transitions.values().sum::<W>().to_f64())
+    }
+
+    /// Whether the state was seen in training following another state, i.e. is in `known_states`.
+    /// Prefer this to reading the field, whose representation may change.
+    pub fn is_known_state(&self, state: &T) -> bool {
+        self.known_states.contains(state)
+    }
+
+    /// The number of known states (see `is_known_state`).
+    pub fn known_state_count(&self) -> usize {
+        self.known_states.len()
+    }
+
+    /// Whether drawing this state after the given sequence would end generation at once: it is
+    /// the sentinel, or nothing but the sentinel can follow it.
+    pub(crate) fn ends_generation(&self, current_sequence: &[T], state: &T) -> bool {
+        if Some(state) == self.sentinel.as_ref() { return true; }
+        let mut extended = current_sequence.to_vec();
+        extended.push(state.clone());
+        match self.best_model(&extended) {
+            Some(transitions) => transitions.iter().all(|(k, weight)| *weight <= W::ZERO || Some(k) == self.sentinel.as_ref()),
+            None => true,
+        }
+    }
+
+    /// Every known state that was never observed followed by another state, such as states that
+    /// only appear at the ends of training sequences.  Unless the model falls back to a unigram
+    /// or marginal model, generation stops when it reaches one of these states.
+    pub fn terminal_states(&self) -> HashSet<T> {
+        self.known_states.iter()
+            .filter(|state| !self.markov_chain.contains_key(std::slice::from_ref(*state)))
+            .cloned()
+            .collect()
+    }
+
+    /// Every known state that was never the first state of a training sequence, so it only ever
+    /// occurred after other states.  Generating from scratch (with a sentinel) can't begin with
+    /// these states, except through priors.  Untraining doesn't update the record of first states.
+    pub fn non_initial_states(&self) -> HashSet<T> {
+        self.known_states.difference(&self.initial_states).cloned().collect()
+    }
+
+    /// Iterate over every transition the model has learned, in every context of every order, as
+    /// `(context, next state, weight)` tuples.  Prefer this to reading `markov_chain` directly when
+    /// exporting or analyzing a model.  The order of iteration is unspecified.
+    pub fn iter_transitions(&self) -> impl Iterator<Item = (&[T], &T, W)> {
+        self.markov_chain.iter().flat_map(|(context, transitions)| {
+            transitions.iter().map(move |(next, weight)| (context.as_slice(), next, *weight))
+        })
+    }
+
+    /// The fraction of all ordered pairs of known states that are stored as transitions from a
+    /// single state, i.e. how dense the order-1 model is.  A low coverage means the model is
+    /// sparse, so priors matter a lot; a high one means generation has many options.  Priors
+    /// stored in the model count as transitions, so measure a model built `without_prior` (or with a
+    /// lazy prior, which isn't stored).  Returns 0.0 if there are no known states.
+    pub fn bigram_coverage(&self) -> f64 {
+        let state_count = self.known_states.len();
+        if state_count == 0 { return 0.0; }
+        let pair_count: usize = self.known_states.iter()
+            .filter_map(|state| self.markov_chain.get(std::slice::from_ref(state)))
+            .map(|transitions| transitions.iter().filter(|(next, weight)| **weight > W::ZERO && self.known_states.contains(*next)).count())
+            .sum();
+        pair_count as f64 / (state_count * state_count) as f64
+    }
+
+    /// Summary statistics about the model, e.g. to check that training actually ingested data.
+    pub fn stats(&self) -> MultiMarkovStats {
+        MultiMarkovStats {
+            context_count: self.markov_chain.len(),
+            known_state_count: self.known_states.len(),
+            total_weight: self.markov_chain.values().flat_map(|transitions| transitions.values()).map(|weight| weight.to_f64()).sum(),
+            max_context_length: self.markov_chain.keys().map(|context| context.len()).max().unwrap_or(0),
+        }
+    }
+
+    /// For each context length from 1 to the model's order, the number of distinct contexts of that
+    /// length, e.g. `[(1, 40), (2, 300), (3, 12)]`.  If the longest contexts are rare, the model
+    /// mostly backs off to shorter ones, and a lower order would do about as well.  Contexts longer
+    /// than the order (which only direct edits of `markov_chain` can add) aren't counted.
+    pub fn order_histogram(&self) -> Vec<(usize, usize)> {
+        let mut counts = vec![0; self.order as usize];
+        for context in self.markov_chain.keys().filter(|context| !context.is_empty()) {
+            if let Some(count) = counts.get_mut(context.len() - 1) {
+                *count += 1;
+            }
+        }
+        counts.into_iter().enumerate().map(|(i, count)| (i + 1, count)).collect()
+    }
+
+    /// An estimate of the memory used by the model, in bytes: the allocated capacity of its maps
+    /// and sets (including the cached weight sums), the vectors holding each context, and the
+    /// remembered training sequences (if any).  It is only approximate, as the exact overhead of
+    /// a hash map is an implementation detail, and memory owned by the states themselves (e.g. the
+    /// contents of a `String`) is not counted.  Useful for comparing e.g. pruned and unpruned models.
+    pub fn memory_usage(&self) -> usize {
+        use std::mem::size_of;
+        // each hash table slot holds an entry plus (roughly) one byte of control data
+        fn table<K, V>(capacity: usize) -> usize { capacity * (size_of::<K>() + size_of::<V>() + 1) }
+        let context_bytes = |context: &Vec<T>| context.capacity() * size_of::<T>();
+        let chain: usize = table::<Vec<T>, StateMap<T,W>>(self.markov_chain.capacity())
+            + self.markov_chain.iter().map(|(context, transitions)| {
+                context_bytes(context) + table::<T, W>(transitions.capacity())
+            }).sum::<usize>();
+        let sums = table::<Vec<T>, WeightSum<T,W>>(self.weight_sums.capacity())
+            + self.weight_sums.iter().map(|(context, cached)| context_bytes(context) + context_bytes(&cached.stable_order)).sum::<usize>()
+            + context_bytes(&self.stable_states);
+        let sequences = self.training_sequences.as_ref().map_or(0, |sequences| {
+            table::<Vec<T>, ()>(sequences.capacity()) + sequences.iter().map(context_bytes).sum::<usize>()
+        });
+        size_of::<Self>() + chain + table::<T, ()>(self.known_states.capacity()) + table::<T, ()>(self.initial_states.capacity())
+            + sums + sequences
+    }
+
+    /// Using the model's random-number generator and the "weights" of the various state transitions
+    /// from the trained model, draw a new state to follow the given sequence.
+    pub fn random_next(&mut self, current_sequence: &[T]) -> Option<T> {
+        let r: f64 = self.rng.gen();
+        self.random_next_from_roll(current_sequence, r)
+    }
+
+    /// Draw a new state to follow each of the given sequences, like `random_next`, e.g. to extend a
+    /// batch of sequences being generated in lockstep.  The results are in the same order as the
+    /// sequences, with `None` for any sequence that no model matches.  The sequences may be
+    /// vectors, arrays or slices.
+    pub fn random_next_batch<S: AsRef<[T]>>(&mut self, sequences: &[S]) -> Vec<Option<T>> {
+        sequences.iter()
+            .map(|sequence| {
+                let r: f64 = self.rng.gen();
+                self.random_next_from_roll(sequence.as_ref(), r)
+            })
+            .collect()
+    }
+
+    /// Like `random_next`, but if the model was built `with_strict_states`, returns
+    /// `MultiMarkovError::UnknownState` if the sequence contains a state never seen in training.
+    pub fn try_random_next(&mut self, current_sequence: &[T]) -> Result<Option<T>,MultiMarkovError> {
+        self.check_states(current_sequence)?;
+        Ok(self.random_next(current_sequence))
+    }
+
+    /// Like `random_next`, but using the given random number generator instead of the model's
+    /// own, so the model is only borrowed immutably.  This lets several threads share one model
+    /// (e.g. in an `Arc`) while each uses its own generator.
+    pub fn random_next_with<R: Rng + ?Sized>(&self, current_sequence: &[T], rng: &mut R) -> Option<T> {
         let r: f64 = rng.gen();
-        let mut randomroll = r*sum_of_weights; // TODO: can this be accomplished in fewer lines?
+        self.random_next_from_roll(current_sequence, r)
+    }
+
+    /// Draw a new state to follow the given sequence, using the random number `r` drawn uniformly
+    /// from [0,1).
+    fn random_next_from_roll(&self, current_sequence: &[T], r: f64) -> Option<T> {
+        if self.lazy_prior.is_some() {
+            // the cached sums don't include the prior
+            return self.draw_weighted(current_sequence, r, |_, weight| weight);
+        }
+        let (context, bestmodel) = self.best_model_entry(current_sequence)?;
+        let sum_of_weights = self.weight_sum(context, bestmodel);
+        if self.stable_order {
+            // the sum was worked out in the same order, so rounding can't differ between runs either
+            return Self::draw(r, self.candidates(context, bestmodel), sum_of_weights);
+        }
+        Self::draw(r, bestmodel.iter().map(|(k,v)| (k, *v)), sum_of_weights)
+    }
+
+    /// Like `random_next`, but with a "temperature" that controls how adventurous the draw is.
+    /// Each weight is raised to the power `1/temperature` before drawing, so temperatures below 1
+    /// favour the most likely states (approaching always picking the most likely state as the
+    /// temperature approaches 0), and temperatures above 1 flatten the distribution toward a
+    /// uniform one.  A temperature of exactly 1 is the same as `random_next`.
+    ///
+    /// Weights are divided by the largest weight before being raised to the power, so very small
+    /// temperatures don't overflow; instead the weights of all but the most likely states
+    /// underflow to zero, and the draw becomes effectively greedy.  Panics if the temperature is
+    /// not greater than zero.
+    pub fn random_next_with_temperature(&mut self, current_sequence: &[T], temperature: f64) -> Option<T> {
+        assert!(temperature>0.0,"Temperature must be greater than zero.");
+        if temperature == 1.0 { return self.random_next(current_sequence); }
+        let bestmodel = self.best_model(current_sequence)?;
+        let max_weight = max_weight(&bestmodel);
+        self.random_next_transformed(current_sequence, |_, weight| tempered(weight, max_weight, temperature))
+    }
+
+    /// Like `random_next`, but only states in `allowed` can be drawn.  Returns `None` if none of
+    /// the possible following states are allowed.
+    pub fn random_next_allowed(&mut self, current_sequence: &[T], allowed: &HashSet<T>) -> Option<T> {
+        self.random_next_transformed(current_sequence, |k, weight| if allowed.contains(k) { weight } else { W::ZERO })
+    }
+
+    /// Like `random_next`, but states in `blocked` will never be drawn.  Returns `None` if all of
+    /// the possible following states are blocked.
+    pub fn random_next_excluding(&mut self, current_sequence: &[T], blocked: &HashSet<T>) -> Option<T> {
+        self.random_next_transformed(current_sequence, |k, weight| if blocked.contains(k) { W::ZERO } else { weight })
+    }
+
+    /// Like `random_next`, but each possible following state's weight is first passed through
+    /// `transform`, which is given the state and its stored weight and returns the weight to draw
+    /// with.  This is the general form of `random_next_with_temperature`, `random_next_allowed` and
+    /// the like, for decoding strategies they don't cover.  States whose transformed weight isn't
+    /// positive are never drawn, and `None` is returned if that leaves nothing to draw.
+    ///
+    /// ```
+    /// use multimarkov::MultiMarkov;
+    /// let mut mm = MultiMarkov::<char>::builder()
+    ///     .train(vec![vec!['f','o','o','b','a','r']])
+    ///     .build();
+    /// // never repeat the previous state
+    /// let next = mm.random_next_transformed(&['o'], |state, weight| if *state == 'o' { 0.0 } else { weight });
+    /// assert_ne!(next, Some('o'));
+    /// ```
+    pub fn random_next_transformed(&mut self, current_sequence: &[T], transform: impl FnMut(&T, W) -> W) -> Option<T> {
+        let r: f64 = self.rng.gen();
+        self.draw_weighted(current_sequence, r, transform)
+    }
+
+    /// Like `random_next`, but using nucleus ("top-p") sampling: only the most probable following
+    /// states whose probabilities together reach at least `p` can be drawn, in proportion to
+    /// their weights.  If the single most probable state already reaches `p`, it is always drawn.
+    /// Ties are broken as in `top_k_next`.  Panics unless `p` is greater than zero and at most 1.
+    pub fn random_next_top_p(&mut self, current_sequence: &[T], p: f64) -> Option<T> {
+        assert!(p > 0.0 && p <= 1.0, "p must be greater than zero and at most 1.");
+        let nucleus = self.nucleus(current_sequence, p);
+        self.random_next_allowed(current_sequence, &nucleus)
+    }
+
+    /// The most probable following states whose probabilities together reach at least `p`.
+    pub(crate) fn nucleus(&self, current_sequence: &[T], p: f64) -> HashSet<T> {
+        let mut ranked = self.ranked_next(current_sequence);
+        let mut cumulative = 0.0;
+        let nucleus_size = ranked.iter()
+            .position(|(_, probability)| { cumulative += probability; cumulative >= p })
+            .map_or(ranked.len(), |i| i + 1);
+        ranked.truncate(nucleus_size);
+        ranked.into_iter().map(|(k, _)| k).collect()
+    }
+
+    /// Draw up to `n` different states to follow the given sequence, without replacement: each is
+    /// drawn like `random_next`, from the possible following states not drawn already.  Unlike
+    /// `top_k_next`, this gives a random but weight-respecting shortlist, e.g. for suggestions.
+    /// If fewer than `n` states can follow the sequence, all of them are returned, in the order drawn.
+    pub fn sample_n_distinct(&mut self, current_sequence: &[T], n: usize) -> Vec<T> {
+        let mut drawn = HashSet::new();
+        let mut sample = Vec::new();
+        while sample.len() < n {
+            match self.random_next_excluding(current_sequence, &drawn) {
+                Some(next) => {
+                    drawn.insert(next.clone());
+                    sample.push(next);
+                },
+                None => break,
+            }
+        }
+        sample
+    }
+
+    /// Return the most likely state to follow the given sequence, without any randomness, using
+    /// the same best-fitted model that `random_next` would draw from.  If several states are
+    /// equally likely, the first one in the model's (fixed) iteration order is returned, so
+    /// repeated calls agree.  Returns `None` if no model matches the sequence.
+    pub fn most_likely_next(&self, current_sequence: &[T]) -> Option<T> {
+        self.most_likely_weighted(current_sequence, |_, weight| weight)
+    }
+
+    /// Like `most_likely_next`, but after passing each possible state's weight through the
+    /// `weight` function.  States whose weight isn't positive are never picked.
+    pub(crate) fn most_likely_weighted(&self, current_sequence: &[T], mut weight: impl FnMut(&T, W) -> W) -> Option<T> {
+        let (context, bestmodel) = self.best_model_entry(current_sequence)?;
+        let mut most_likely: Option<(&T,W)> = None;
+        for (k,v) in self.candidates(context, bestmodel) {
+            let v = weight(k, v);
+            if v > W::ZERO && most_likely.is_none_or(|(_, best)| v > best) {
+                most_likely = Some((k, v));
+            }
+        }
+        most_likely.map(|(k,_)| k.clone())
+    }
+
+    /// Like `random_next_transformed`, but using the random number `r` drawn uniformly from [0,1).
+    fn draw_weighted(&self, current_sequence: &[T], r: f64, mut weight: impl FnMut(&T, W) -> W) -> Option<T> {
+        let (context, bestmodel) = self.best_model_entry(current_sequence)?;
+        let weights: Vec<(&T,W)> = self.candidates(context, bestmodel).into_iter()
+            .map(|(k,v)| {
+                let weight = weight(k, v);
+                // anything not positive, including NaN, can't be drawn
+                (k, if weight > W::ZERO { weight } else { W::ZERO })
+            })
+            .collect();
+        let sum_of_weights: W = weights.iter().map(|(_,v)| v).sum();
+        Self::draw(r, weights, sum_of_weights)
+    }
+
+    /// Pick one of the given states in proportion to its weight, using a random number `r` drawn
+    /// uniformly from [0,1).  `sum_of_weights` must be the sum of the weights.
+    fn draw<'a>(r: f64, weights: impl IntoIterator<Item = (&'a T, W)>, sum_of_weights: W) -> Option<T>
+        where T: 'a
+    {
+        if sum_of_weights <= W::ZERO { return None; }
+        let mut randomroll = W::from_f64(r)*sum_of_weights; // TODO: can this be accomplished in fewer lines?
         // every state has a chance of being selected in proportion to its 'weight' as fraction of the sum of weights
-        for (k,v) in bestmodel {
-            if randomroll > *v {
+        let mut last = None;
+        for (k,v) in weights {
+            if v <= W::ZERO { continue; }
+            if randomroll > v {
                 randomroll -= v;
+                last = Some(k);
             } else {
                 return Some(k.clone());
             }
         }
-        None // this should never be reached
+        // rounding in the subtractions can leave a roll close to the sum just above the last weight
+        last.cloned()
+    }
+
+    /// Find the best-fitted model for the given sequence (the same one `random_next` would draw
+    /// from) and return its possible following states with their probabilities, normalized to sum
+    /// to 1.  Returns `None` if no model matches the sequence.
+    pub fn next_distribution(&self, current_sequence: &[T]) -> Option<HashMap<T,f64>> {
+        let bestmodel = self.best_model(current_sequence)?;
+        let sum_of_weights = bestmodel.values().sum::<W>().to_f64();
+        Some(bestmodel.iter().map(|(k,v)| (k.clone(), v.to_f64() / sum_of_weights)).collect())
+    }
+
+    /// The Shannon entropy (in nats) of `next_distribution` for the given sequence: zero where only
+    /// one state can follow, and largest where the model is least sure what comes next.  Returns
+    /// `None` if no model matches the sequence.
+    pub fn context_entropy(&self, current_sequence: &[T]) -> Option<f64> {
+        Some(entropy(self.next_distribution(current_sequence)?.values()))
+    }
+
+    /// The entropy of each context's distribution (as in `context_entropy`), averaged over every
+    /// context of every order, as a single measure of how varied the model's output is.  Both the
+    /// entropy and the weight of each context come from its smoothed distribution, i.e. including
+    /// any lazy prior: a context is weighted by the total of its smoothed weights, which without a
+    /// lazy prior is its `context_count`.  Returns `NaN` for an untrained model.
+    pub fn mean_entropy(&self) -> f64 {
+        let mut total_entropy = 0.0;
+        let mut total_weight = 0.0;
+        for transitions in self.markov_chain.values() {
+            let transitions = self.apply_lazy_prior(transitions);
+            let sum_of_weights = transitions.values().sum::<W>().to_f64();
+            let probabilities: Vec<f64> = transitions.values().map(|v| v.to_f64() / sum_of_weights).collect();
+            total_entropy += sum_of_weights * entropy(probabilities.iter());
+            total_weight += sum_of_weights;
+        }
+        total_entropy / total_weight
+    }
+
+    /// Return the `k` most probable states to follow the given sequence, with their normalized
+    /// probabilities, most probable first.  Uses the same best-fitted model as `random_next`.
+    /// Returns an empty vector if no model matches the sequence.
+    pub fn top_k_next(&self, current_sequence: &[T], k: usize) -> Vec<(T,f64)> {
+        let mut ranked = self.ranked_next(current_sequence);
+        ranked.truncate(k);
+        ranked
+    }
+
+    /// The 1-based rank of `next` among the possible following states of the given sequence, most
+    /// probable first, in the same order as `top_k_next` (so `top_k_next(sequence, rank)` ends
+    /// with `next`).  A low rank means an observed continuation was unsurprising to the model.
+    /// Returns `None` if no model matches the sequence or `next` can't follow it.
+    pub fn next_rank(&self, current_sequence: &[T], next: &T) -> Option<usize> {
+        self.ranked_next(current_sequence).iter()
+            .position(|(k, _)| k == next)
+            .map(|i| i + 1)
+    }
+
+    /// All possible following states from the best-fitted model with their normalized
+    /// probabilities, sorted most probable first.  The sort is stable, so ties keep the model's
+    /// (fixed) iteration order and repeated calls agree.
+    fn ranked_next(&self, current_sequence: &[T]) -> Vec<(T,f64)> {
+        let (context, bestmodel) = match self.best_model_entry(current_sequence) {
+            Some(entry) => entry,
+            None => return Vec::new(),
+        };
+        let candidates = self.candidates(context, bestmodel);
+        let sum_of_weights = candidates.iter().map(|(_,v)| v).sum::<W>().to_f64();
+        let mut ranked: Vec<(T,f64)> = candidates.into_iter().map(|(k,v)| (k.clone(), v.to_f64() / sum_of_weights)).collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranked
+    }
+
+    /// A context's transitions as a list, followed by the lazy prior (if any) for every known state
+    /// not observed following it.  If the model keeps a stable order (see
+    /// `MultiMarkovBuilder::with_stable_order`), each part is in the order `refresh_weight_sums`
+    /// sorted it into, by a fixed hash of each state; otherwise the maps' iteration order is used.
+    pub(crate) fn candidates<'a>(&'a self, context: &[T], transitions: &'a StateMap<T,W>) -> Vec<(&'a T,W)> {
+        let cached = self.weight_sums.get(context).filter(|cached| self.stable_order && cached.len == transitions.len());
+        let mut candidates: Vec<(&T,W)> = match cached {
+            Some(cached) => cached.stable_order.iter()
+                .filter_map(|state| transitions.get_key_value(state))
+                .map(|(k,v)| (k, *v))
+                .collect(),
+            None => transitions.iter().map(|(k,v)| (k, *v)).collect(),
+        };
+        if self.stable_order && (cached.is_none() || candidates.len() != transitions.len()) {
+            // not sorted when the sums were refreshed, or changed since
+            candidates = sorted_transitions(transitions);
+        }
+        if let Some(prior) = self.lazy_prior {
+            let unseen = |state: &&T| !transitions.contains_key(*state);
+            if !self.stable_order {
+                candidates.extend(self.known_states.iter().filter(unseen).map(|state| (state, prior)));
+            } else if self.stable_states.len() == self.known_states.len() {
+                candidates.extend(self.stable_states.iter().filter(unseen).map(|state| (state, prior)));
+            } else {
+                let mut states: Vec<&T> = self.known_states.iter().filter(unseen).collect();
+                states.sort_by_key(|state| stable_hash(*state));
+                candidates.extend(states.into_iter().map(|state| (state, prior)));
+            }
+        }
+        candidates
+    }
+
+    /// Like `next_distribution`, but instead of using only the best-fitted model, blend the
+    /// normalized distributions of the models for every tail of the sequence, from the longest
+    /// (length `order`) down to length 1.  `lambdas[0]` is the weight given to the longest tail's
+    /// model, `lambdas[1]` the weight for the next-longest, and so on.  If the sequence is too short
+    /// for some tails, or no model was trained for them (or they are longer than `max_context`),
+    /// their weights are shared out between the remaining models in proportion to their lambdas.
+    /// Returns `None` if no model matches at all.
+    ///
+    /// An order-0 model has only the empty context, so it takes no lambdas and returns that
+    /// context's distribution.  Otherwise, panics unless there is exactly one lambda per order,
+    /// none are negative, and they sum to 1.
+    pub fn interpolated_distribution(&self, current_sequence: &[T], lambdas: &[f64]) -> Option<HashMap<T,f64>> {
+        assert_eq!(lambdas.len(), self.order as usize, "There must be one lambda for each order of the model.");
+        if self.order == 0 { return self.next_distribution(&[]); }
+        assert!(lambdas.iter().all(|l| *l >= 0.0), "Lambdas must not be negative.");
+        assert!((lambdas.iter().sum::<f64>() - 1.0).abs() < 1e-9, "Lambdas must sum to 1.");
+        let mut distribution: HashMap<T,f64> = HashMap::new();
+        let mut total_lambda = 0.0;
+        for (context, model) in self.matching_models(current_sequence).filter(|(context, _)| !context.is_empty()) {
+            let lambda = lambdas[self.order as usize - context.len()];
+            if lambda == 0.0 { continue; }
+            let model = self.apply_lazy_prior(model);
+            let sum_of_weights = model.values().sum::<W>().to_f64();
+            for (k,v) in model.iter() {
+                *distribution.entry(k.clone()).or_insert(0.0) += lambda * v.to_f64() / sum_of_weights;
+            }
+            total_lambda += lambda;
+        }
+        if total_lambda == 0.0 { return None; }
+        distribution.values_mut().for_each(|v| *v /= total_lambda);
+        Some(distribution)
+    }
+
+    /// Like `interpolated_distribution`, but with the blend weights derived from the training data
+    /// instead of fixed lambdas.  Starting from the shortest tail of the sequence with a model,
+    /// each longer tail's model is blended in with weight `n / (n + t)`, where `n` is its
+    /// `context_count` and `t` the number of distinct states that followed it (as in Witten-Bell
+    /// interpolation): well-supported contexts dominate, and sparse ones defer to shorter tails.
+    /// Tails with no model are skipped, so if only short tails match, only they are used.  Returns
+    /// `None` if no model matches at all.
+    pub fn adaptive_interpolated_distribution(&self, current_sequence: &[T]) -> Option<HashMap<T,f64>> {
+        let mut distribution: Option<HashMap<T,f64>> = None;
+        let matching: Vec<_> = self.matching_models(current_sequence).collect();
+        for (_, model) in matching.into_iter().rev() {
+            let model = self.apply_lazy_prior(model);
+            let count = model.values().sum::<W>().to_f64();
+            if count <= 0.0 { continue; }
+            distribution = Some(match distribution {
+                None => model.iter().map(|(k,v)| (k.clone(), v.to_f64() / count)).collect(),
+                Some(mut lower) => {
+                    let lambda = count / (count + model.len() as f64);
+                    lower.values_mut().for_each(|v| *v *= 1.0 - lambda);
+                    for (k,v) in model.iter() {
+                        *lower.entry(k.clone()).or_insert(0.0) += lambda * v.to_f64() / count;
+                    }
+                    lower
+                },
+            });
+        }
+        distribution
+    }
+
+    /// Generate a whole sequence by repeatedly drawing random next states, starting from the
+    /// given (possibly empty) sequence of states.  The returned sequence begins with `start`.
+    ///
+    /// If the model was built with sentinels (see `MultiMarkovBuilder::with_boundaries` and
+    /// `with_sentinel`), generation begins as if the start sentinels padded `start`, as they did
+    /// each training sequence, and stops when the end sentinel is drawn; the sentinels themselves
+    /// are never included in the result.  Generation also stops when no model is found for
+    /// the current sequence, or when it reaches the maximum length (see
+    /// `MultiMarkovBuilder::with_max_length`), but not before it reaches the minimum length if
+    /// it can help it (see `MultiMarkovBuilder::with_min_length`).  Without a sentinel, `start`
+    /// must not be empty unless the model has a unigram model (see `MultiMarkovBuilder::with_unigrams`).
+    ///
+    /// ```
+    /// use multimarkov::MultiMarkov;
+    /// let input_vec = vec![
+    ///     vec!['f','o','o','b','a','r'],
+    ///     vec!['b','a','z'],
+    /// ];
+    /// let mut mm = MultiMarkov::<char>::builder()
+    ///     .with_sentinel('#')
+    ///     .with_max_length(10)
+    ///     .train(input_vec)
+    ///     .build();
+    /// let word = mm.generate_sequence(&[]);
+    /// assert!(word.len() <= 10);
+    /// assert!(!word.contains(&'#'));
+    /// ```
+    pub fn generate_sequence(&mut self, start: &[T]) -> Vec<T> {
+        let mut sequence = start.to_vec();
+        let remaining = self.max_length.saturating_sub(start.len());
+        sequence.extend(self.generator(start).take(remaining));
+        sequence
+    }
+
+    /// Generate a sequence of exactly `length` states (including `start`), e.g. for fixed-length
+    /// codes.  Unlike `generate_sequence`, the sentinel is never drawn and states that would end
+    /// the sequence are avoided until the last one, and the maximum length is ignored.  The decode
+    /// mode and repetition penalty apply as usual.  Returns `None` if generation reaches a dead
+    /// end (a sequence no model matches) first, or if `start` is already longer than `length`.
+    pub fn generate_exact(&mut self, length: usize, start: &[T]) -> Option<Vec<T>> {
+        if start.len() > length { return None; }
+        let mut sequence = start.to_vec();
+        sequence.extend(self.generator(start).with_min_length(length).take(length - start.len()));
+        if sequence.len() == length { Some(sequence) } else { None }
+    }
+
+    /// Like `generate_sequence`, but also stops as soon as `stop` returns `true`.  `stop` is called
+    /// with the whole sequence generated so far (including `start`) each time a state is appended,
+    /// so it can express any rule, such as stopping after two particular states in a row.  The
+    /// sentinel and the maximum length still end generation too, as a backstop.  The minimum length
+    /// only keeps the sentinel and states with no following states from being drawn, so `stop`
+    /// can end a sequence before it is reached.
+    ///
+    /// ```
+    /// use multimarkov::MultiMarkov;
+    /// let mut mm = MultiMarkov::<char>::builder()
+    ///     .with_order(1)
+    ///     .without_prior()
+    ///     .with_marginal_fallback()
+    ///     .train(vec![vec!['f','o','o','b','a','r']])
+    ///     .build();
+    /// let up_to_vowel = mm.generate_sequence_until(&['b'], |sequence| sequence.ends_with(&['a']));
+    /// assert_eq!(up_to_vowel, vec!['b','a']);
+    /// ```
+    pub fn generate_sequence_until(&mut self, start: &[T], mut stop: impl FnMut(&[T]) -> bool) -> Vec<T> {
+        let mut sequence = start.to_vec();
+        let remaining = self.max_length.saturating_sub(start.len());
+        for next in self.generator(start).take(remaining) {
+            sequence.push(next);
+            if stop(&sequence) { break; }
+        }
+        sequence
+    }
+
+    /// Find the most probable continuations of `start`, by beam search: extend each of the
+    /// `beam_width` best partial sequences by each of their `beam_width` most probable next states
+    /// (from the best-fitted model, as in `top_k_next`), keep the `beam_width` best results by
+    /// total log-probability, and repeat `length` times.  Returns up to `beam_width` sequences,
+    /// each beginning with `start`, with their log-probabilities (of the added states only), most
+    /// probable first.
+    ///
+    /// As in `generate_sequence`, the model's sentinel (if any) is taken to precede `start`.  A
+    /// sequence ends early if the sentinel is the next state (which is scored, but not included),
+    /// or if no model matches it.  Panics if `beam_width` is 0.
+    pub fn beam_search(&self, start: &[T], length: usize, beam_width: usize) -> Vec<(Vec<T>,f64)> {
+        assert!(beam_width > 0, "Beam width must be greater than zero.");
+        let mut beams: Vec<(Vec<T>,f64)> = vec![(start.to_vec(), 0.0)];
+        let mut finished: Vec<(Vec<T>,f64)> = Vec::new();
+        for _ in 0..length {
+            let mut candidates: Vec<(Vec<T>,f64)> = Vec::new();
+            for (sequence, score) in beams {
+                let context = self.start_context(&sequence);
+                let ranked = self.ranked_next(&context);
+                if ranked.is_empty() {
+                    finished.push((sequence, score));
+                    continue;
+                }
+                for (next, probability) in ranked.into_iter().take(beam_width).filter(|(_, p)| *p > 0.0) {
+                    let score = score + probability.ln();
+                    if Some(&next) == self.sentinel.as_ref() {
+                        finished.push((sequence.clone(), score));
+                    } else {
+                        let mut extended = sequence.clone();
+                        extended.push(next);
+                        candidates.push((extended, score));
+                    }
+                }
+            }
+            candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+            candidates.truncate(beam_width);
+            beams = candidates;
+            if beams.is_empty() { break; }
+        }
+        finished.extend(beams);
+        finished.sort_by(|a, b| b.1.total_cmp(&a.1));
+        finished.truncate(beam_width);
+        finished
+    }
+
+    /// Generate `n` whole sequences from scratch with `generate_sequence`.  This requires a model
+    /// built with a sentinel (or a unigram model), since there is no starting sequence.
+    pub fn generate_sequences(&mut self, n: usize) -> Vec<Vec<T>> {
+        (0..n).map(|_| self.generate_sequence(&[])).collect()
+    }
+
+    /// Whether the sequence appears verbatim in the training data, e.g. to check that a generated
+    /// name isn't just one of the inputs.  Returns `None` if the model wasn't built with
+    /// `MultiMarkovBuilder::with_remembered_inputs`, since then it can't tell.
+    pub fn is_training_sequence(&self, sequence: &[T]) -> Option<bool> {
+        self.training_sequences.as_ref().map(|training| training.contains(sequence))
+    }
+
+    /// Like `generate_sequences`, but rejects (and tries again for) any generated sequence that
+    /// appears verbatim in the training data.  At most `max_retries` rejections are allowed in
+    /// total; if a highly constrained model hits that cap, the novel sequences generated so far are
+    /// returned, so the result may contain fewer than `n` sequences.
+    ///
+    /// Panics if the model wasn't built with `MultiMarkovBuilder::with_remembered_inputs`, because
+    /// then there is no way to tell which sequences are in the training data.
+    pub fn generate_novel_sequences(&mut self, n: usize, max_retries: usize) -> Vec<Vec<T>> {
+        assert!(self.training_sequences.is_some(),"Training sequences were not remembered; build the model with `with_remembered_inputs()`.");
+        let mut sequences = Vec::with_capacity(n);
+        let mut retries: usize = 0;
+        while sequences.len() < n {
+            let sequence = self.generate_sequence(&[]);
+            if self.is_training_sequence(&sequence) == Some(true) {
+                retries += 1;
+                if retries > max_retries { break; }
+            } else {
+                sequences.push(sequence);
+            }
+        }
+        sequences
+    }
+
+    /// Generate states lazily, one per call to `next()`, following on from the given sequence.
+    /// See `Generator` for when the iterator stops.
+    ///
+    /// ```
+    /// use multimarkov::MultiMarkov;
+    /// let mut mm = MultiMarkov::<char>::builder()
+    ///     .train(vec![vec!['f','o','o','b','a','r']])
+    ///     .build();
+    /// // stops early if it reaches a state with no following states, such as 'r'
+    /// let up_to_five: String = mm.generator(&['o']).take(5).collect();
+    /// assert!(up_to_five.chars().count() <= 5);
+    /// ```
+    pub fn generator(&mut self, start: &[T]) -> Generator<'_, T, StdRng, W> {
+        let rng = StdRng::seed_from_u64(self.rng.gen());
+        Generator::new(self, start, rng)
+    }
+
+    /// Like `generator`, but drawing with the given random number generator instead of one seeded
+    /// from the model's own, so the model is only borrowed immutably (as in `random_next_with`).
+    pub fn generator_with<R: Rng>(&self, start: &[T], rng: R) -> Generator<'_, T, R, W> {
+        Generator::new(self, start, rng)
+    }
+
+    /// Precompute alias tables for every context, returning a sampler that draws following states
+    /// in constant time, for workloads that draw from the same contexts many times.  Build it once
+    /// training is complete; it does not see later changes to the model.  The sampler's random
+    /// number generator is seeded from this model's, so seeded models give reproducible samplers.
+    pub fn build_sampler(&mut self) -> AliasSampler<T, W> {
+        let rng = StdRng::from_rng(&mut self.rng).expect("Could not seed the sampler's random number generator.");
+        AliasSampler::new(self, rng)
+    }
+
+    /// The probability of `next` following the given sequence, under the same best-fitted model
+    /// that `random_next` would draw from, normalized over all its possible following states.
+    ///
+    /// Returns 0.0 if no model matches the sequence, or if `next` was never observed following it
+    /// (unless priors were applied, in which case the transition has the prior probability).
+    pub fn transition_probability(&self, current_sequence: &[T], next: &T) -> f64 {
+        match self.best_model(current_sequence) {
+            Some(model) => {
+                let weight = model.get(next).map_or(0.0, |weight| weight.to_f64());
+                let sum_of_weights = model.values().sum::<W>().to_f64();
+                weight / sum_of_weights
+            },
+            None => 0.0,
+        }
+    }
+
+    /// Like `transition_probability`, but if the model was built `with_strict_states`, returns
+    /// `MultiMarkovError::UnknownState` if the sequence or `next` contains a state never seen in
+    /// training.
+    pub fn try_transition_probability(&self, current_sequence: &[T], next: &T) -> Result<f64,MultiMarkovError> {
+        self.check_states(current_sequence)?;
+        self.check_states(std::slice::from_ref(next))?;
+        Ok(self.transition_probability(current_sequence, next))
+    }
+
+    /// A cheap "stupid backoff" score for ranking candidate states to follow the given sequence.
+    /// If `next` was observed following the longest tail of the sequence the model knows about
+    /// (up to `order` states), the score is its relative frequency there; otherwise the next
+    /// shorter tail is tried, and the score is multiplied by `alpha` for every state dropped from
+    /// the context.  `DEFAULT_BACKOFF_ALPHA` (0.4) is the usual choice.
+    ///
+    /// Scores are not probabilities and don't sum to 1.  They use the weights stored in the model,
+    /// so in a model built with a flat prior, every known state is found in the first context and
+    /// nothing backs off; build with `without_prior` (or a lazy prior) for classic stupid backoff.
+    /// Returns 0.0 if `next` was never observed following any tail of the sequence.
+    pub fn stupid_backoff_score(&self, current_sequence: &[T], next: &T, alpha: f64) -> f64 {
+        let longest = min(self.max_context as usize, current_sequence.len());
+        for (context, transitions) in self.matching_models(current_sequence) {
+            let weight = transitions.get(next).map_or(0.0, |weight| weight.to_f64());
+            if weight > 0.0 {
+                let sum_of_weights = self.weight_sum(context, transitions).to_f64();
+                return alpha.powi((longest - context.len()) as i32) * weight / sum_of_weights;
+            }
+        }
+        0.0
+    }
+
+    /// Calculate the probability of a whole sequence under the model, by multiplying together the
+    /// probability of each of its state transitions.  Each transition's probability is taken from
+    /// the same best-fitted model that `random_next` would draw from, normalized so that the weights
+    /// of all the possible following states sum to 1.  If the model was built with sentinels, the
+    /// sequence is scored as if they were added to its start and end, as in training.
+    ///
+    /// A transition that was not observed in training contributes the prior probability if priors
+    /// were applied, or zero otherwise.  For long sequences, prefer `sequence_log_probability` to
+    /// avoid floating point underflow.
+    pub fn sequence_probability(&self, sequence: &[T]) -> f64 {
+        self.sequence_log_probability(sequence).exp()
+    }
+
+    /// Calculate the natural logarithm of the probability of a whole sequence under the model (see
+    /// `sequence_probability`), by summing the logarithms of its transition probabilities.  Returns
+    /// negative infinity if any transition in the sequence is impossible under the model.  If the
+    /// model was built with a backoff penalty (see `MultiMarkovBuilder::with_backoff_penalty`),
+    /// transitions scored with a shortened context are penalized.
+    pub fn sequence_log_probability(&self, sequence: &[T]) -> f64 {
+        let sequence = self.add_sentinels(sequence);
+        (self.first_scored()..sequence.len())
+            .map(|i| self.scored_log_probability(&sequence[..i], &sequence[i]))
+            .sum()
+    }
+
+    /// The log of `transition_probability`, less the backoff penalty (if any) for each state the
+    /// best-fitted model's context is shorter than it could be.
+    fn scored_log_probability(&self, current_sequence: &[T], next: &T) -> f64 {
+        let log_probability = self.transition_probability(current_sequence, next).ln();
+        if self.backoff_penalty == 1.0 { return log_probability; }
+        let full = min(self.max_context as usize, current_sequence.len());
+        let matched = self.best_model_entry(current_sequence).map_or(0, |(context, _)| context.len());
+        log_probability + (full - matched) as f64 * self.backoff_penalty.ln()
+    }
+
+    /// Calculate the perplexity of the model over a corpus of test sequences: the exponential of
+    /// the average negative log-probability per state transition, using `sequence_log_probability`
+    /// to score each sequence.  Lower is better; a perplexity of `k` means the model is as uncertain
+    /// as if it were choosing uniformly between `k` states at every step.
+    ///
+    /// Transitions not observed in training are scored with the prior probability if priors were
+    /// applied.  Without priors they have zero probability, and the perplexity will be infinite if
+    /// the test corpus contains any of them; they are not skipped.  Returns `NaN` if the test
+    /// sequences contain no transitions at all.  The sequences may be vectors, arrays or slices.
+    pub fn perplexity<S: AsRef<[T]>>(&self, sequences: &[S]) -> f64 {
+        let mut total_log_probability = 0.0;
+        let mut transition_count: usize = 0;
+        for sequence in sequences {
+            let sequence = sequence.as_ref();
+            let transitions = (sequence.len() + self.start_padding() + usize::from(self.sentinel.is_some())).saturating_sub(self.first_scored());
+            if transitions == 0 { continue; }
+            total_log_probability += self.sequence_log_probability(sequence);
+            transition_count += transitions;
+        }
+        (-total_log_probability / transition_count as f64).exp()
+    }
+
+    /// Calculate the cross-entropy between the model and a corpus of held-out test sequences: the
+    /// average negative natural log-probability per state transition, scored as in `perplexity`
+    /// (which is its exponential).  Lower is better, so it can be used to tune the order and
+    /// smoothing of a model on data it wasn't trained on.
+    ///
+    /// Transitions to or from a state that never occurred in the training data can't be scored by
+    /// the model, so `oov` says what to do with them: score them with a floor probability, skip
+    /// them, or return an error.  Other transitions not observed in training have zero probability
+    /// unless the model has priors, making the cross-entropy infinite.  Returns `NaN` if there are
+    /// no transitions to score.
+    pub fn cross_entropy<S: AsRef<[T]>>(&self, sequences: &[S], oov: OovHandling) -> Result<f64,MultiMarkovError> {
+        let mut total_log_probability = 0.0;
+        let mut transition_count: usize = 0;
+        for sequence in sequences {
+            let sequence = self.add_sentinels(sequence.as_ref());
+            for i in self.first_scored()..sequence.len() {
+                let log_probability = if (i > 0 && self.is_unknown_state(&sequence[i-1])) || self.is_unknown_state(&sequence[i]) {
+                    match oov {
+                        OovHandling::Floor(probability) => probability.ln(),
+                        OovHandling::Skip => continue,
+                        OovHandling::Error => return Err(MultiMarkovError::UnknownState),
+                    }
+                } else {
+                    self.scored_log_probability(&sequence[..i], &sequence[i])
+                };
+                total_log_probability += log_probability;
+                transition_count += 1;
+            }
+        }
+        Ok(-total_log_probability / transition_count as f64)
+    }
+
+    /// Classify a sequence by which of several labelled models gives it the highest
+    /// `sequence_log_probability`, e.g. to identify the language of a word with one model per
+    /// language.  Returns the winning model's label, or `None` if there are no models.  Ties
+    /// (including every model finding the sequence impossible) go to the first model listed.
+    ///
+    /// ```
+    /// use multimarkov::MultiMarkov;
+    /// let english = MultiMarkov::<char>::builder().train_text("the quick brown fox").build();
+    /// let welsh = MultiMarkov::<char>::builder().train_text("llwynog brown cyflym").build();
+    /// let models = [("en", &english), ("cy", &welsh)];
+    /// assert_eq!(MultiMarkov::classify(&models, &['l','l','w']), Some("cy"));
+    /// ```
+    pub fn classify<'a>(models: &[(&'a str, &Self)], sequence: &[T]) -> Option<&'a str> {
+        let mut best: Option<(&'a str, f64)> = None;
+        for (label, model) in models {
+            let score = model.sequence_log_probability(sequence);
+            if best.is_none_or(|(_, best_score)| score > best_score) {
+                best = Some((label, score));
+            }
+        }
+        best.map(|(label, _)| label)
+    }
+
+    /// Whether a state never occurred in the training data, either following another state or
+    /// (like the first state of a training sequence) being followed by one.  The start sentinel
+    /// always occurred.
+    fn is_unknown_state(&self, state: &T) -> bool {
+        Some(state) != self.start_sentinel.as_ref()
+            && !self.known_states.contains(state) && !self.markov_chain.contains_key(std::slice::from_ref(state))
+    }
+
+    /// In a model built `with_strict_states`, an error if the sequence contains an unknown state.
+    fn check_states(&self, sequence: &[T]) -> Result<(),MultiMarkovError> {
+        if self.strict_states && sequence.iter().any(|state| self.is_unknown_state(state)) {
+            return Err(MultiMarkovError::UnknownState);
+        }
+        Ok(())
+    }
+
+    /// If the model has sentinels, return a copy of the sequence with the start sentinels added to
+    /// its start and the end sentinel to its end, the same way training sequences were treated.
+    fn add_sentinels(&self, sequence: &[T]) -> Vec<T> {
+        let mut bounded = self.start_context(sequence);
+        bounded.extend(self.sentinel.iter().cloned());
+        bounded
+    }
+
+    /// A copy of the sequence preceded by the start sentinels, if the model has them, as the
+    /// context to generate from.
+    pub(crate) fn start_context(&self, sequence: &[T]) -> Vec<T> {
+        let mut context = Vec::with_capacity(self.start_padding() + sequence.len() + 1);
+        if let Some(start) = &self.start_sentinel {
+            context.extend(std::iter::repeat_n(start.clone(), self.start_padding()));
+        }
+        context.extend_from_slice(sequence);
+        context
+    }
+
+    /// How many start sentinels precede each sequence: `order` (at least one) if the model has them.
+    fn start_padding(&self) -> usize {
+        if self.start_sentinel.is_some() { self.order.max(1) as usize } else { 0 }
+    }
+
+    /// The position in a sequence with sentinels added of the first state that is scored: the one
+    /// after the start sentinels, or without them the second state (or, in an order-0 model, the
+    /// first state, which needs no context).
+    fn first_scored(&self) -> usize {
+        if self.start_sentinel.is_some() { self.start_padding() } else if self.order > 0 { 1 } else { 0 }
     }
 
     /// For a given sequence, find the most tightly-fitted model we have for its tail-end subsequence.
     /// For example, if the sequence is `['t','r','u','s']`, and self.order==3, first see if we have
     /// a model for `['r','u','s']`, which will only exist if that sequence has been seen in the training
     /// data.  If not, see if we have a model for `['u','s']`, and failing that, see if we have a
-    /// model for `['s']`.  If no model for `['s']` is found, return `None`.
-    fn best_model(&self, current_sequence: &Vec<T>) ->  Option<&HashMap<T,f64>> {
-        // If current_sequence.len() is at least self.order, count "i" down from self.order to 1,
-        // taking sequence slices of length "i" and checking if we have a matching model:
-        for i in (1..(min(self.order as usize, current_sequence.len())+1)).rev() {
-            let subsequence = &current_sequence[(current_sequence.len()-i)..current_sequence.len()];
-            if self.markov_chain.contains_key(subsequence) {
-                return self.markov_chain.get(subsequence);
-            }
+    /// model for `['s']`.  If no model for `['s']` is found, fall back to the unigram model for
+    /// the empty sequence `[]` if one was trained, or else return `None`.
+    ///
+    /// If the model has a lazy prior, it is filled in for every known state not observed following
+    /// the matched sequence.
+    fn best_model(&self, current_sequence: &[T]) ->  Option<Cow<'_, StateMap<T,W>>> {
+        self.best_model_entry(current_sequence).map(|(_, model)| self.apply_lazy_prior(model))
+    }
+
+    /// A context's transitions, with the lazy prior (if any) filled in for every known state not
+    /// observed following the context.
+    fn apply_lazy_prior<'a>(&self, transitions: &'a StateMap<T,W>) -> Cow<'a, StateMap<T,W>> {
+        match self.lazy_prior {
+            Some(prior) => {
+                let mut filled = transitions.clone();
+                for state in self.known_states.iter() {
+                    filled.entry(state.clone()).or_insert(prior);
+                }
+                Cow::Owned(filled)
+            },
+            None => Cow::Borrowed(transitions),
         }
-        None
+    }
+
+    /// Like `best_model`, but also return the context the model was found under.
+    fn best_model_entry(&self, current_sequence: &[T]) -> Option<(&[T], &StateMap<T,W>)> {
+        self.matching_models(current_sequence).next()
+    }
+
+    /// Every tail of the sequence (up to `max_context` states long) that the model has a model
+    /// for, with that model's transitions, longest first: the first is the best-fitted model
+    /// `random_next` draws from, and the rest are what it would back off to in turn.  The
+    /// transitions are as stored in `markov_chain`, without any lazy prior.  They are found
+    /// lazily, so taking only the first few skips the lookups for the rest.
+    pub fn matching_models<'a, 's>(&'a self, current_sequence: &'s [T]) -> impl Iterator<Item = (&'a [T], &'a StateMap<T,W>)> + 's
+        where 'a: 's
+    {
+        tails(current_sequence, self.max_context)
+            .filter_map(move |tail| self.markov_chain.get_key_value(tail))
+            .map(|(context, model)| (context.as_slice(), model))
     }
 
 }
 
+impl<T, W> MultiMarkov<T, W>
+    where T: Eq + Hash + Clone + Display, W: Weight
+{
+    /// Describe the model as a [GraphViz](https://graphviz.org/) digraph in DOT format, e.g. to
+    /// visualize what a small model has learned.  Each context is a node, labelled with its states
+    /// separated by spaces, with an edge to each state that can follow it, labelled with the
+    /// transition's weight.  Edges lighter than `min_weight` are left out, so pass a `min_weight`
+    /// above the prior to hide unobserved transitions, or 0.0 to include everything.
+    ///
+    /// In a model of order 1, every context is a single state, so the graph is the Markov chain.
+    pub fn to_dot(&self, min_weight: W) -> String {
+        let mut dot = String::from("digraph multimarkov {\n");
+        for (context, transitions) in &self.markov_chain {
+            for (next, weight) in transitions.iter().filter(|(_, weight)| **weight >= min_weight) {
+                writeln!(dot, "    {} -> {} [label=\"{}\"];", dot_node(context), dot_node(std::slice::from_ref(next)), weight)
+                    .expect("Writing to a String cannot fail.");
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
 
+    /// Export the stored transition weights as tab-separated values, one `context\tnext\tweight`
+    /// row per transition, for spreadsheets and text tools.  The states of a context are separated
+    /// by single spaces (the empty context is an empty field).  Rows are sorted, so the output is
+    /// the same every time and diffs well.
+    ///
+    /// The weights are those stored in the model, so build it `without_prior` (or with a lazy prior,
+    /// which isn't stored) to export the raw training counts, e.g. for `MultiMarkovBuilder::from_counts`.
+    pub fn to_tsv(&self) -> String {
+        let mut rows: Vec<String> = self.iter_transitions().map(|(context, next, weight)| {
+            let context = context.iter().map(|state| state.to_string()).collect::<Vec<String>>().join(" ");
+            format!("{}\t{}\t{}\n", context, next, weight)
+        }).collect();
+        rows.sort();
+        rows.concat()
+    }
+}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl<T, W> MultiMarkov<T, W>
+    where T: Eq + Hash + Clone + Display + Ord, W: Weight
+{
+    /// A plain-text listing of the model for quick inspection, e.g. in logs or snapshot tests.
+    /// Each context gets a line listing its possible following states, each with its normalized
+    /// probability and, in parentheses, its stored weight.  Contexts and states are sorted, so the
+    /// output is the same every time.  The model doesn't record which weights are priors, but a
+    /// lazy prior (which isn't stored) is noted on a line of its own at the start.
+    ///
+    /// ```
+    /// use multimarkov::MultiMarkov;
+    /// let mm = MultiMarkov::<char>::builder()
+    ///     .with_order(1)
+    ///     .without_prior()
+    ///     .train(vec![vec!['b','a','r'], vec!['b','a','z']])
+    ///     .build();
+    /// assert_eq!(mm.dump(), "[a] -> r: 0.5000 (1), z: 0.5000 (1)\n[b] -> a: 1.0000 (2)\n");
+    /// ```
+    pub fn dump(&self) -> String {
+        let mut dump = String::new();
+        if let Some(prior) = self.lazy_prior {
+            writeln!(dump, "lazy prior: {}", prior).expect("Writing to a String cannot fail.");
+        }
+        let mut contexts: Vec<&Vec<T>> = self.markov_chain.keys().collect();
+        contexts.sort();
+        for context in contexts {
+            let transitions = &self.markov_chain[context];
+            let sum_of_weights = transitions.values().sum::<W>().to_f64();
+            let mut followers: Vec<(&T, &W)> = transitions.iter().collect();
+            followers.sort_by(|a, b| a.0.cmp(b.0));
+            let followers: Vec<String> = followers.into_iter()
+                .map(|(next, weight)| format!("{}: {:.4} ({})", next, weight.to_f64() / sum_of_weights, weight))
+                .collect();
+            let context: Vec<String> = context.iter().map(|state| state.to_string()).collect();
+            writeln!(dump, "[{}] -> {}", context.join(" "), followers.join(", ")).expect("Writing to a String cannot fail.");
+        }
+        dump
+    }
+}
 
-    fn char_data() -> Vec<Vec<char>> {
-        vec![
-            vec!['a'], // can't be used, but should be skipped over rather than causing error to propagate
-            vec!['a','c','e'],
-            vec!['f','o','o','b','a','r'],
-            vec!['b','a','z'],
-        ]
+/// An untrained model with the default settings, equivalent to `MultiMarkov::builder().build()`.
+impl<T, W> Default for MultiMarkov<T, W>
+    where T: Eq + Hash + Clone, W: Weight
+{
+    fn default() -> Self {
+        MultiMarkov::<T>::builder().build_with_weights()
     }
+}
 
-    #[test]
-    fn test_model_builder_works() {
-        let mm = MultiMarkov::<char>::builder()
-            .with_order(2)
-            .with_prior(0.015)
-            .train(char_data().into_iter())
-            .build();
-        assert!(mm.random_next(&vec!['a','b','c']).is_some()); // random draw didn't fail (because 'c' is in training data)
-        assert!(mm.random_next(&vec!['x','y','z']).is_none()); // 'z' is in training data only at end of sequence; no following states were observed so there's no model
+/// Two models are equal if they have the same order, known states and transition weights.  Weights
+/// are compared exactly, and other settings (e.g. the sentinel) and the random number generator
+/// are ignored.
+impl<T, W> PartialEq for MultiMarkov<T, W>
+    where T: Eq + Hash + Clone, W: Weight
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.order == other.order
+            && self.known_states == other.known_states
+            && self.markov_chain == other.markov_chain
     }
+}
 
-    #[test]
-    fn test_model_weights_and_priors_are_correct() {
-        let mm = MultiMarkov::<char>::builder()
-            .with_order(2)
-            .with_prior(0.001)
-            .train(char_data().into_iter())
-            .build();
-        let chain = &mm.markov_chain;
-        assert_eq!(*chain.get(&*vec!['b']).unwrap().get(&'a').unwrap(),2.0); // seen twice in training data
-        assert_eq!(*chain.get(&*vec!['a']).unwrap().get(&'c').unwrap(),1.0); // seen once in training data
-        assert_eq!(*chain.get(&*vec!['a']).unwrap().get(&'e').unwrap(),0.001); // not observed in training data; assigned a 'prior' probability
+/// Train a model with the default settings on a collection of sequences, equivalent to
+/// `MultiMarkov::builder().train(sequences).build()`.  As with `train`, sequences that can't be
+/// trained (e.g. because they are too short) are skipped rather than causing a panic.
+impl<T, W> FromIterator<Vec<T>> for MultiMarkov<T, W>
+    where T: Eq + Hash + Clone, W: Weight
+{
+    fn from_iter<I: IntoIterator<Item = Vec<T>>>(sequences: I) -> Self {
+        MultiMarkov::<T>::builder().train(sequences).build_with_weights()
     }
+}
+
+/// A quoted DOT node ID for a sequence of states.
+fn dot_node<T: Display>(states: &[T]) -> String {
+    let label = states.iter().map(|state| state.to_string()).collect::<Vec<String>>().join(" ");
+    format!("\"{}\"", label.replace('\\', "\\\\").replace('"', "\\\""))
+}
 
-}
\ No newline at end of file
+
+/// Summary statistics about a trained model, returned by `MultiMarkov::stats`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MultiMarkovStats {
+    /// The number of distinct sequences (of any length) for which a model of following states exists.
+    pub context_count: usize,
+    /// The number of distinct states seen in the training data.
+    pub known_state_count: usize,
+    /// The sum of all transition weights stored in the model, including any priors (but not a lazy prior).
+    pub total_weight: f64,
+    /// The length of the longest sequence for which a model exists; at most the model's order.
+    pub max_context_length: usize,
+}
+
+/// How `MultiMarkov::cross_entropy` treats transitions to or from out-of-vocabulary states, i.e.
+/// states that never occurred in the training data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OovHandling {
+    /// Score each such transition with this probability, e.g. the prior the model was built with.
+    Floor(f64),
+    /// Leave such transitions out of the average altogether.
+    Skip,
+    /// Return `MultiMarkovError::UnknownState`.
+    Error,
+}
+
+/// The tails of the sequence that lookups try, longest first: if the sequence is at least
+/// `max_context` (normally the order) states long, count "i" down from `max_context` to 0, taking
+/// the last "i" states each time.
+pub(crate) fn tails<T>(sequence: &[T], max_context: i32) -> impl Iterator<Item = &[T]> {
+    (0..(min(max_context as usize, sequence.len())+1)).rev()
+        .map(move |i| &sequence[(sequence.len()-i)..])
+}
+
+/// A context's cached weight sum, with the number of transitions that were summed, so that a
+/// context changed since (or missing from a deserialized model) can be told apart, and the
+/// following states in stable order if the model keeps one.
+#[derive(Clone, Debug)]
+struct WeightSum<T, W> {
+    sum: W,
+    len: usize,
+    stable_order: Vec<T>,
+}
+
+/// The largest of a context's weights, or zero if it has none.
+pub(crate) fn max_weight<T, W: Weight>(transitions: &StateMap<T,W>) -> W {
+    transitions.values().fold(W::ZERO, |max, weight| if *weight > max { *weight } else { max })
+}
+
+/// A weight divided by the context's largest weight (so it can't overflow) and raised to the power
+/// `1/temperature`, for drawing with a temperature.
+pub(crate) fn tempered<W: Weight>(weight: W, max_weight: W, temperature: f64) -> W {
+    W::from_f64((weight / max_weight).to_f64().powf(1.0 / temperature))
+}
+
+/// The Shannon entropy (in nats) of a normalized distribution.
+fn entropy<'a>(probabilities: impl Iterator<Item = &'a f64>) -> f64 {
+    -probabilities.filter(|p| **p > 0.0).map(|p| p * p.ln()).sum::<f64>()
+}
+
+/// A hash of the state that is the same in every run of the program (unlike a `HashMap`'s), for
+/// putting states in a reproducible order.
+fn stable_hash<T: Hash>(state: &T) -> u64 {
+    let mut hasher = Fnv1a::default();
+    state.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A context's transitions as a list, sorted by `stable_hash`.
+fn sorted_transitions<T: Hash, W: Weight>(transitions: &StateMap<T,W>) -> Vec<(&T,W)> {
+    let mut sorted: Vec<(&T,W)> = transitions.iter().map(|(k,v)| (k, *v)).collect();
+    sorted.sort_by_key(|(k,_)| stable_hash(*k));
+    sorted
+}
+
+/// The 64-bit FNV-1a hash, which is fixed by its definition, unlike the standard library's
+/// `DefaultHasher`, whose algorithm may change between Rust versions.  Integers are hashed as
+/// little-endian bytes, and `usize` as 64 bits, so the hash is the same on every platform too.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+}
+
+/// Create a random number generator seeded from `rand::thread_rng()`.
+pub(crate) fn thread_seeded_rng() -> StdRng {
+    StdRng::from_rng(rand::thread_rng()).expect("Could not seed the random number generator.")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn char_data() -> Vec<Vec<char>> {
+        vec![
+            vec!['a'], // can't be used, but should be skipped over rather than causing error to propagate
+            vec!['a','c','e'],
+            vec!['f','o','o','b','a','r'],
+            vec!['b','a','z'],
+        ]
+    }
+
+    #[test]
+    fn test_model_builder_works() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_prior(0.015)
+            .train(char_data())
+            .build();
+        assert!(mm.random_next(&['a','b','c']).is_some()); // random draw didn't fail (because 'c' is in training data)
+        assert!(mm.random_next(&['x','y','z']).is_none()); // 'z' is in training data only at end of sequence; no following states were observed so there's no model
+    }
+
+    #[test]
+    fn test_model_weights_and_priors_are_correct() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_prior(0.001)
+            .train(char_data())
+            .build();
+        let chain = &mm.markov_chain;
+        assert_eq!(*chain.get(&*vec!['b']).unwrap().get(&'a').unwrap(),2.0); // seen twice in training data
+        assert_eq!(*chain.get(&*vec!['a']).unwrap().get(&'c').unwrap(),1.0); // seen once in training data
+        assert_eq!(*chain.get(&*vec!['a']).unwrap().get(&'e').unwrap(),0.001); // not observed in training data; assigned a 'prior' probability
+    }
+
+    #[test]
+    fn test_seeded_models_draw_identical_sequences() {
+        // each model is trained separately, so its HashMaps list the states in their own order
+        let draws = || {
+            let mut mm = MultiMarkov::<char>::builder()
+                .with_order(2)
+                .with_seed(1234)
+                .train(char_data())
+                .build();
+            (0..50).map(|_| mm.random_next(&['a'])).collect::<Vec<Option<char>>>()
+        };
+        let first = draws();
+        for _ in 0..10 {
+            assert_eq!(draws(), first);
+        }
+    }
+
+    #[test]
+    fn test_order_is_readable_after_build() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .train(char_data())
+            .build();
+        assert_eq!(mm.order(), 2);
+        let mm = MultiMarkov::<char>::builder()
+            .train(char_data())
+            .build();
+        assert_eq!(mm.order(), MultiMarkov::<char>::DEFAULT_ORDER);
+    }
+
+    #[test]
+    fn test_train_in_one_call() {
+        let mm = MultiMarkov::<char>::train(char_data(), Some(2), Some(0.001)).unwrap();
+        assert_eq!(mm.order(), 2);
+        assert_eq!(*mm.markov_chain.get(&*vec!['a']).unwrap().get(&'e').unwrap(),0.001); // prior was applied
+        let mm = MultiMarkov::<char>::train(char_data(), None, None).unwrap();
+        assert_eq!(mm.order(), MultiMarkov::<char>::DEFAULT_ORDER);
+        assert!(!mm.markov_chain.get(&*vec!['a']).unwrap().contains_key(&'e')); // no prior was applied
+    }
+
+    #[test]
+    fn test_train_in_one_call_rejects_bad_input() {
+        assert!(matches!(MultiMarkov::<char>::train(char_data(), Some(-1), None), Err(MultiMarkovError::InvalidOrder(-1))));
+        assert!(matches!(MultiMarkov::<char>::train(Vec::<Vec<char>>::new(), None, None), Err(MultiMarkovError::EmptyInput)));
+        assert!(matches!(MultiMarkov::<char>::train(std::iter::empty::<Vec<char>>(), None, None), Err(MultiMarkovError::EmptyInput)));
+        // every sequence is too short to train, so nothing was
+        assert!(matches!(MultiMarkov::<char>::train(vec![vec!['a'], vec![]], None, None), Err(MultiMarkovError::EmptyInput)));
+    }
+
+    #[test]
+    fn test_generate_sequence_stops_at_sentinel_or_max_length() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_sentinel('#')
+            .with_max_length(5)
+            .with_seed(99)
+            .train(char_data())
+            .build();
+        for _ in 0..100 {
+            let generated = mm.generate_sequence(&[]);
+            assert!(generated.len() <= 5);
+            assert!(!generated.contains(&'#'));
+        }
+        let generated = mm.generate_sequence(&['f','o']);
+        assert!(generated.starts_with(&['f','o']));
+    }
+
+    #[test]
+    fn test_generate_sequence_without_sentinel_stops_at_dead_end() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        // 'z' has no following states, so generation can't continue past it
+        assert_eq!(mm.generate_sequence(&['a','z']), vec!['a','z']);
+        assert!(mm.generate_sequence(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_sequence_probability() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        assert_eq!(mm.sequence_probability(&['b','a']), 1.0); // 'b' is always followed by 'a'
+        assert_eq!(mm.sequence_probability(&['b','a','z']), 0.5); // ['b','a'] was followed by 'r' once and 'z' once
+        assert_eq!(mm.sequence_probability(&['b','a','e']), 0.0); // never observed
+        assert_eq!(mm.sequence_log_probability(&['b','a','e']), f64::NEG_INFINITY);
+        assert!((mm.sequence_log_probability(&['b','a','z']) - 0.5_f64.ln()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_sequence_probability_at_order_0() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(0)
+            .without_prior()
+            .train(vec![vec!['a','b'], vec!['a','a']])
+            .build();
+        // every state is scored, the first included, by its overall frequency: 'a' 3/4, 'b' 1/4
+        assert!((mm.sequence_probability(&['a']) - 0.75).abs() < 1e-12);
+        assert!((mm.sequence_probability(&['a','b']) - 3.0 / 16.0).abs() < 1e-12);
+        let expected = (-(0.75_f64.ln() + 0.25_f64.ln()) / 2.0).exp();
+        assert!((mm.perplexity(&[vec!['a','b']]) - expected).abs() < 1e-12);
+        let cross_entropy = mm.cross_entropy(&[vec!['a','b']], OovHandling::Error).unwrap();
+        assert!((cross_entropy - expected.ln()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_sequence_probability_uses_priors_and_sentinels() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_prior(0.001)
+            .train(char_data())
+            .build();
+        assert!(mm.sequence_probability(&['b','a','e']) > 0.0); // unobserved, but has a prior
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .with_sentinel('#')
+            .train(char_data())
+            .build();
+        // "baz" is a complete training sequence, but "ba" never ended a sequence
+        assert!(mm.sequence_probability(&['b','a','z']) > 0.0);
+        assert_eq!(mm.sequence_probability(&['b','a']), 0.0);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_model_round_trips_through_serde() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_prior(0.001)
+            .train(char_data())
+            .build();
+        let bytes = bincode::serialize(&mm).unwrap();
+        let mut loaded: MultiMarkov<char> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(loaded.order(), 2);
+        assert_eq!(loaded.markov_chain, mm.markov_chain);
+        assert_eq!(loaded.known_states, mm.known_states);
+        // the weight sums aren't serialized, but draws don't need them
+        assert!(loaded.weight_sums.is_empty());
+        assert!(loaded.random_next(&['b','a']).is_some());
+        loaded.refresh_weight_sums();
+        assert_eq!(loaded.weight_sums.len(), mm.weight_sums.len());
+    }
+
+    #[test]
+    fn test_perplexity() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        assert_eq!(mm.perplexity(&[vec!['b','a']]), 1.0); // a certain transition
+        assert!((mm.perplexity(&[vec!['b','a','z']]) - 2.0_f64.sqrt()).abs() < 1e-12); // probabilities 1.0 and 0.5
+        assert_eq!(mm.perplexity(&[vec!['b','a','e']]), f64::INFINITY); // unobserved, and no priors
+        assert!(mm.perplexity(&[vec!['a']]).is_nan()); // no transitions
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_prior(0.001)
+            .train(char_data())
+            .build();
+        assert!(mm.perplexity(&[vec!['b','a','e']]).is_finite());
+    }
+
+    #[test]
+    fn test_next_distribution() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        let distribution = mm.next_distribution(&['b','a']).unwrap();
+        assert_eq!(distribution.len(), 2);
+        assert_eq!(*distribution.get(&'r').unwrap(), 0.5);
+        assert_eq!(*distribution.get(&'z').unwrap(), 0.5);
+        let distribution = mm.next_distribution(&['o']).unwrap();
+        assert!((distribution.values().sum::<f64>() - 1.0).abs() < 1e-12);
+        assert!(mm.next_distribution(&['z']).is_none());
+    }
+
+    #[test]
+    fn test_interpolated_distribution() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        // ['b','a'] is followed by 'r' or 'z'; ['a'] is followed by 'c', 'r' or 'z'
+        let distribution = mm.interpolated_distribution(&['b','a'], &[0.6, 0.4]).unwrap();
+        let order_1 = mm.next_distribution(&['a']).unwrap();
+        assert!((distribution.get(&'r').unwrap() - (0.6*0.5 + 0.4*order_1.get(&'r').unwrap())).abs() < 1e-12);
+        assert!((distribution.get(&'c').unwrap() - 0.4*order_1.get(&'c').unwrap()).abs() < 1e-12);
+        assert!((distribution.values().sum::<f64>() - 1.0).abs() < 1e-12);
+        // with only one state of context, the order-1 model gets all the weight
+        assert_eq!(mm.interpolated_distribution(&['a'], &[0.6, 0.4]), mm.next_distribution(&['a']));
+        assert!(mm.interpolated_distribution(&['z'], &[0.6, 0.4]).is_none());
+    }
+
+    #[test]
+    fn test_interpolated_distribution_at_order_0() {
+        let mm = MultiMarkov::<char>::builder()
+            .try_with_order(0).unwrap()
+            .without_prior()
+            .train(char_data())
+            .build();
+        let distribution = mm.interpolated_distribution(&['b','a'], &[]).unwrap();
+        assert_eq!(Some(distribution), mm.next_distribution(&[]));
+    }
+
+    #[test]
+    #[should_panic(expected="Lambdas must sum to 1.")]
+    fn test_interpolated_distribution_validates_lambdas() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .train(char_data())
+            .build();
+        mm.interpolated_distribution(&['b','a'], &[0.6, 0.6]);
+    }
+
+    #[test]
+    fn test_word_level_model_with_string_states() {
+        let sentences = [
+            "the cat sat on the mat",
+            "the dog sat on the log",
+        ];
+        let mut mm = MultiMarkov::<String>::builder()
+            .with_order(2)
+            .without_prior()
+            .with_sentinel(String::from("<s>"))
+            .train(sentences.iter().map(|s| s.split(' ').map(String::from)))
+            .build();
+        let sentence = mm.generate_sequence(&[]);
+        assert_eq!(sentence.first().map(String::as_str), Some("the"));
+        assert_eq!(sentence.get(2).map(String::as_str), Some("sat"));
+        let on_the = [String::from("on"), String::from("the")];
+        assert_eq!(mm.next_distribution(&on_the).unwrap().get("mat"), Some(&0.5));
+        assert!(mm.sequence_probability(&sentence) > 0.0);
+    }
+
+    #[test]
+    fn test_top_k_next() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_prior(0.001)
+            .train(char_data())
+            .build();
+        let top = mm.top_k_next(&['x','b'], 2);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].0, 'a'); // 'b' was always followed by 'a' in training
+        assert!(top[0].1 > top[1].1);
+        assert_eq!(mm.top_k_next(&['x','b'], 100).len(), mm.known_states.len());
+        assert_eq!(mm.top_k_next(&['x','b'], 5), mm.top_k_next(&['x','b'], 5));
+        assert!(mm.top_k_next(&['z'], 3).is_empty());
+    }
+
+    #[test]
+    fn test_random_next_with_temperature() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_prior(0.001)
+            .with_seed(7)
+            .train(char_data())
+            .build();
+        // a tiny temperature always picks the most likely state
+        for _ in 0..100 {
+            assert_eq!(mm.random_next_with_temperature(&['b'], 0.001), Some('a'));
+        }
+        // a temperature of 1 reproduces random_next exactly, even in a separately trained model
+        let seeded = || MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_prior(0.001)
+            .with_seed(7)
+            .train(char_data())
+            .build();
+        for _ in 0..10 {
+            let (mut mm2, mut mm3) = (seeded(), seeded());
+            let draws: Vec<Option<char>> = (0..50).map(|_| mm2.random_next(&['o'])).collect();
+            let tempered: Vec<Option<char>> = (0..50).map(|_| mm3.random_next_with_temperature(&['o'], 1.0)).collect();
+            assert_eq!(draws, tempered);
+        }
+        // a high temperature makes unlikely states much more common
+        let unlikely = (0..1000).filter(|_| mm.random_next_with_temperature(&['b'], 100.0) != Some('a')).count();
+        assert!(unlikely > 500);
+    }
+
+    #[test]
+    fn test_most_likely_next() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_prior(0.001)
+            .train(char_data())
+            .build();
+        assert_eq!(mm.most_likely_next(&['b']), Some('a'));
+        assert_eq!(mm.most_likely_next(&['x','f']), Some('o')); // backs off to ['f'] like random_next
+        // ['b','a'] is followed by 'r' and 'z' equally often; the tie resolves the same way every time
+        let first = mm.most_likely_next(&['b','a']);
+        assert!(first == Some('r') || first == Some('z'));
+        for _ in 0..10 {
+            assert_eq!(mm.most_likely_next(&['b','a']), first);
+        }
+        assert_eq!(mm.most_likely_next(&['z']), None);
+    }
+
+    #[test]
+    fn test_transition_probability() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        assert_eq!(mm.transition_probability(&['b','a'], &'z'), 0.5);
+        assert_eq!(mm.transition_probability(&['x','b'], &'a'), 1.0); // backs off to ['b']
+        assert_eq!(mm.transition_probability(&['b','a'], &'e'), 0.0);
+        assert_eq!(mm.transition_probability(&['z'], &'a'), 0.0);
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_prior(0.5)
+            .train(char_data())
+            .build();
+        // ['b','a'] -> 'r' and 'z' seen once each, plus a prior of 0.5 for each of the 5 other known states
+        assert!((mm.transition_probability(&['b','a'], &'e') - 0.5/4.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        let stats = mm.stats();
+        assert_eq!(stats.context_count, mm.markov_chain.len());
+        assert_eq!(stats.known_state_count, 7);
+        assert_eq!(stats.total_weight, 15.0); // 2 + 5 + 2 transitions, each counted at order 1 and (except the first) order 2
+        assert_eq!(stats.max_context_length, 2);
+        let empty = MultiMarkov::<char>::builder().build().stats();
+        assert_eq!(empty.context_count, 0);
+        assert_eq!(empty.max_context_length, 0);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .train(char_data())
+            .build();
+        mm.clear();
+        assert_eq!(mm.order(), 2);
+        assert!(mm.markov_chain.is_empty());
+        assert!(mm.known_states.is_empty());
+        assert!(mm.random_next(&['b','a']).is_none());
+        assert!(mm.next_distribution(&['b']).is_none());
+    }
+
+    #[test]
+    fn test_clear_forgets_remembered_sequences() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_remembered_inputs()
+            .train(char_data())
+            .build();
+        mm.clear();
+        assert_eq!(mm.is_training_sequence(&['b','a','z']), Some(false));
+    }
+
+    #[test]
+    fn test_best_model_falls_back_to_unigrams() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .with_unigrams()
+            .train(vec![vec!['q'], vec!['b','a','z']])
+            .build();
+        // 'z' never has a following state, but the unigram model does
+        assert_eq!(mm.random_next(&['a','z']), Some('q'));
+        assert_eq!(mm.random_next(&[]), Some('q'));
+        assert_eq!(mm.transition_probability(&['b'], &'a'), 1.0); // longer models are still preferred
+    }
+
+    #[test]
+    fn test_marginal_fallback_keeps_generation_going() {
+        let mut strict = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        assert!(strict.random_next(&['a','z']).is_none());
+        let mut lenient = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .with_marginal_fallback()
+            .train(char_data())
+            .build();
+        assert!(lenient.random_next(&['a','z']).is_some());
+        assert!(lenient.random_next(&['x']).is_some()); // even a state never seen in training
+        assert_eq!(lenient.transition_probability(&['x'], &'a'), 2.0/9.0);
+    }
+
+    #[test]
+    fn test_generate_sequences() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_sentinel('#')
+            .train(char_data())
+            .build();
+        let sequences = mm.generate_sequences(10);
+        assert_eq!(sequences.len(), 10);
+        assert!(sequences.iter().all(|s| !s.contains(&'#')));
+    }
+
+    #[test]
+    fn test_generate_novel_sequences_rejects_training_data() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .with_sentinel('#')
+            .with_remembered_inputs()
+            .train(char_data())
+            .build();
+        let training: HashSet<Vec<char>> = char_data().into_iter().collect();
+        let sequences = mm.generate_novel_sequences(10, 1000);
+        assert!(sequences.iter().all(|s| !training.contains(s)));
+        // a model that can only reproduce its training data gives up at the retry cap
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .with_sentinel('#')
+            .with_remembered_inputs()
+            .train(vec![vec!['a','b','c']])
+            .build();
+        assert!(mm.generate_novel_sequences(3, 50).is_empty());
+    }
+
+    #[test]
+    fn test_is_training_sequence() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_remembered_inputs()
+            .train(char_data())
+            .build();
+        assert_eq!(mm.is_training_sequence(&['b','a','z']), Some(true));
+        assert_eq!(mm.is_training_sequence(&['b','a']), Some(false));
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .train(char_data())
+            .build();
+        assert_eq!(mm.is_training_sequence(&['b','a','z']), None);
+    }
+
+    #[test]
+    fn test_random_next_with_allowed_and_blocked_states() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_prior(0.001)
+            .train(char_data())
+            .build();
+        let vowels: HashSet<char> = ['a','e','o'].into_iter().collect();
+        for _ in 0..100 {
+            assert!(vowels.contains(&mm.random_next_allowed(&['f'], &vowels).unwrap()));
+            let next = mm.random_next_excluding(&['f'], &vowels).unwrap();
+            assert!(!vowels.contains(&next));
+        }
+        let everything: HashSet<char> = mm.known_states.iter().cloned().collect();
+        assert!(mm.random_next_excluding(&['f'], &everything).is_none());
+        assert!(mm.random_next_allowed(&['f'], &HashSet::new()).is_none());
+    }
+
+    #[test]
+    fn test_random_next_top_p() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_prior(0.001)
+            .train(char_data())
+            .build();
+        // 'r' and 'z' are equally likely after ['b','a'], and together take almost all the weight
+        let top = mm.top_k_next(&['b','a'], 1)[0].0;
+        let mut drawn = HashSet::new();
+        for _ in 0..200 {
+            assert_eq!(mm.random_next_top_p(&['b','a'], 0.4), Some(top));
+            drawn.insert(mm.random_next_top_p(&['b','a'], 0.9).unwrap());
+        }
+        assert_eq!(drawn, ['r','z'].into_iter().collect());
+    }
+
+    #[test]
+    #[should_panic(expected="p must be greater than zero and at most 1.")]
+    fn test_random_next_top_p_validates_p() {
+        let mut mm = MultiMarkov::<char>::builder().train(char_data()).build();
+        mm.random_next_top_p(&['b','a'], 0.0);
+    }
+
+    #[test]
+    fn test_weight_sums_follow_direct_edits() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .without_prior()
+            .train(char_data())
+            .build();
+        assert_eq!(mm.weight_sums.get(&vec!['o']).map(|cached| cached.sum), Some(2.0));
+        mm.markov_chain.get_mut(&vec!['o']).unwrap().insert('x', 8.0);
+        mm.refresh_weight_sums();
+        assert_eq!(mm.weight_sums.get(&vec!['o']).map(|cached| cached.sum), Some(10.0));
+        assert!((0..100).any(|_| mm.random_next(&['o']) == Some('x')));
+    }
+
+    #[test]
+    fn test_stale_weight_sums_are_not_used() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .without_prior()
+            .train(char_data())
+            .build();
+        // without a refresh, the cached sum of 2.0 would leave 'x' almost never drawn
+        mm.markov_chain.get_mut(&vec!['o']).unwrap().insert('x', 1000.0);
+        let drawn_x = (0..200).filter(|_| mm.random_next(&['o']) == Some('x')).count();
+        assert!(drawn_x > 180);
+    }
+
+    #[test]
+    fn test_normalize() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_seed(4)
+            .train(char_data())
+            .build();
+        let before = mm.next_distribution(&['b','a']).unwrap();
+        assert!(!mm.is_normalized());
+        mm.normalize();
+        assert!(mm.is_normalized());
+        for transitions in mm.markov_chain.values() {
+            assert!((transitions.values().sum::<f64>() - 1.0).abs() < 1e-9);
+        }
+        let after = mm.next_distribution(&['b','a']).unwrap();
+        for (state, probability) in before {
+            assert!((after[&state] - probability).abs() < 1e-9);
+        }
+        assert!(mm.random_next(&['b','a']).is_some());
+    }
+
+    #[test]
+    fn test_f32_weights_match_f64_weights() {
+        let builder = || MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_lazy_prior(0.01)
+            .with_seed(6)
+            .train(char_data());
+        let wide = builder().build();
+        let mut narrow = builder().build_with_weights::<f32>();
+        assert_eq!(narrow.markov_chain[&vec!['b','a']][&'r'], 1.0f32);
+        for (state, probability) in wide.next_distribution(&['b','a']).unwrap() {
+            assert!((narrow.transition_probability(&['b','a'], &state) - probability).abs() < 1e-6);
+        }
+        assert!((narrow.sequence_log_probability(&['f','o','o']) - wide.sequence_log_probability(&['f','o','o'])).abs() < 1e-5);
+        assert!(narrow.random_next(&['b','a']).is_some());
+        assert!(narrow.random_next_with_temperature(&['b','a'], 0.5).is_some());
+        assert!(narrow.generator(&['f']).take(5).count() > 0);
+        assert!(narrow.memory_usage() < wide.memory_usage());
+        narrow.normalize();
+        for transitions in narrow.markov_chain.values() {
+            assert!((transitions.values().sum::<f32>() - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .with_prior(0.01)
+            .train(char_data())
+            .build();
+        let dot = mm.to_dot(0.0);
+        assert!(dot.starts_with("digraph multimarkov {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(dot.contains("    \"o\" -> \"o\" [label=\"1\"];\n"));
+        assert!(dot.contains("    \"b\" -> \"a\" [label=\"2\"];\n"));
+        assert!(dot.contains("[label=\"0.01\"]"));
+        let observed_only = mm.to_dot(0.5);
+        assert!(!observed_only.contains("[label=\"0.01\"]"));
+        assert_eq!(observed_only.lines().count(), 2 + 8); // the eight observed transitions
+    }
+
+    #[test]
+    fn test_blend() {
+        let base = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        let niche = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(vec![vec!['b','a','a']])
+            .build();
+        let blended = base.blend(&niche, 0.75).unwrap();
+        assert!(blended.is_normalized());
+        let after_ba = blended.next_distribution(&['b','a']).unwrap();
+        assert!((after_ba[&'r'] - 0.375).abs() < 1e-9);
+        assert!((after_ba[&'z'] - 0.375).abs() < 1e-9);
+        assert!((after_ba[&'a'] - 0.25).abs() < 1e-9);
+        // only the base model has seen ['a','c'], and the niche model has nothing to back off to
+        assert_eq!(blended.next_distribution(&['a','c']), base.next_distribution(&['a','c']));
+        let other_order = MultiMarkov::<char>::builder().with_order(1).train(char_data()).build();
+        assert!(matches!(base.blend(&other_order, 0.5), Err(MultiMarkovError::OrderMismatch(2, 1))));
+    }
+
+    #[test]
+    fn test_kl_divergence() {
+        let reference = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .train(char_data())
+            .build();
+        assert_eq!(reference.kl_divergence(&reference), 0.0);
+        let drifted = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .train(char_data())
+            .train(vec![vec!['a','r']])
+            .build();
+        let by_context = reference.kl_divergence_by_context(&drifted);
+        assert!(by_context[&vec!['a']] > 0.0);
+        assert!(by_context[&vec!['o']].abs() < 1e-12);
+        assert!((reference.kl_divergence(&drifted) - by_context.values().sum::<f64>()).abs() < 1e-12);
+        let unsmoothed = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .without_prior()
+            .train(vec![vec!['a','c','e']])
+            .build();
+        assert_eq!(reference.kl_divergence(&unsmoothed), f64::INFINITY);
+    }
+
+    #[test]
+    fn test_iter_transitions() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        let transitions: Vec<(&[char], &char, f64)> = mm.iter_transitions().collect();
+        assert_eq!(transitions.len(), mm.markov_chain.values().map(|t| t.len()).sum::<usize>());
+        assert!(transitions.contains(&(&['b','a'][..], &'z', 1.0)));
+        assert!(transitions.contains(&(&['b'][..], &'a', 2.0)));
+        let total: f64 = transitions.iter().map(|(_, _, weight)| weight).sum();
+        assert_eq!(total, mm.stats().total_weight);
+    }
+
+    #[test]
+    fn test_generate_sequence_until() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .with_marginal_fallback()
+            .with_max_length(50)
+            .train(char_data())
+            .build();
+        for _ in 0..50 {
+            let generated = mm.generate_sequence_until(&['f'], |sequence| sequence.ends_with(&['o','o']));
+            assert!(generated.len() <= 50);
+            if generated.len() < 50 {
+                assert!(generated.ends_with(&['o','o']));
+                // it stopped at the first occurrence
+                assert!(!generated[..generated.len()-1].windows(2).any(|w| w == ['o','o']));
+            }
+        }
+        assert_eq!(mm.generate_sequence_until(&['f'], |_| true).len(), 2);
+    }
+
+    #[test]
+    fn test_default_and_collect() {
+        let empty = MultiMarkov::<char>::default();
+        assert_eq!(empty.order(), MultiMarkov::<char>::DEFAULT_ORDER);
+        assert!(empty.markov_chain.is_empty());
+        let collected: MultiMarkov<char> = char_data().into_iter().collect();
+        let built = MultiMarkov::<char>::builder().train(char_data()).build();
+        assert_eq!(collected.markov_chain, built.markov_chain);
+    }
+
+    #[test]
+    fn test_beam_search() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_prior(0.001)
+            .train(char_data())
+            .build();
+        let beams = mm.beam_search(&['f'], 3, 3);
+        assert_eq!(beams.len(), 3);
+        assert_eq!(beams[0].0, vec!['f','o','o','b']);
+        assert!((beams[0].1 - mm.beam_search(&['f'], 3, 1)[0].1).abs() < 1e-12);
+        assert!(beams.windows(2).all(|w| w[0].1 >= w[1].1));
+        assert!(beams.iter().all(|(sequence, _)| sequence.len() <= 4 && sequence[0] == 'f'));
+        // generation stops at the sentinel, which is scored but not included
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .with_sentinel('#')
+            .train(char_data())
+            .build();
+        assert_eq!(mm.beam_search(&['a','c'], 10, 2), vec![(vec!['a','c','e'], 0.0)]);
+    }
+
+    #[test]
+    fn test_terminal_states() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .train(char_data())
+            .build();
+        assert_eq!(mm.terminal_states(), ['e','r','z'].into_iter().collect());
+    }
+
+    #[test]
+    fn test_dump_is_sorted_and_notes_lazy_prior() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_lazy_prior(0.01)
+            .train(char_data())
+            .build();
+        let dump = mm.dump();
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines[0], "lazy prior: 0.01");
+        assert_eq!(lines.len(), 1 + mm.markov_chain.len());
+        // sorted by context, shorter contexts first
+        let position = |prefix: &str| lines.iter().position(|line| line.starts_with(prefix)).unwrap();
+        assert!(position("[a] ") < position("[a c] ") && position("[a c] ") < position("[b] "));
+        assert!(lines.contains(&"[b a] -> r: 0.5000 (1), z: 0.5000 (1)"));
+        assert_eq!(dump, mm.dump());
+    }
+
+    #[test]
+    fn test_context_count() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        assert_eq!(mm.context_count(&['b']), 2.0);
+        assert_eq!(mm.context_count(&['b','a']), 2.0);
+        assert_eq!(mm.context_count(&['o','o']), 1.0);
+        // no backing off: there's a model for ['o'], but not for ['x','o']
+        assert_eq!(mm.context_count(&['x','o']), 0.0);
+    }
+
+    #[test]
+    fn test_random_next_with_caller_rng() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..50 {
+            let next = mm.random_next_with(&['b','a'], &mut rng).unwrap();
+            assert!(next == 'r' || next == 'z');
+        }
+        assert_eq!(mm.random_next_with(&['z'], &mut rand::thread_rng()), None);
+    }
+
+    #[test]
+    fn test_stupid_backoff_score() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        let alpha = MultiMarkov::<char>::DEFAULT_BACKOFF_ALPHA;
+        // ['b','a'] was followed by 'r' once and 'z' once
+        assert_eq!(mm.stupid_backoff_score(&['b','a'], &'r', alpha), 0.5);
+        // 'c' never followed ['b','a'], but followed ['a'] once in its three observations
+        assert!((mm.stupid_backoff_score(&['b','a'], &'c', alpha) - 0.4 / 3.0).abs() < 1e-12);
+        // ['x','o'] is unseen, so the shorter context ['o'] is scored, with one backoff
+        assert!((mm.stupid_backoff_score(&['x','o'], &'b', alpha) - 0.4 * 0.5).abs() < 1e-12);
+        assert_eq!(mm.stupid_backoff_score(&['b','a'], &'e', alpha), 0.0);
+    }
+
+    #[test]
+    fn test_clone_and_compare_models() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .train(char_data())
+            .build();
+        let mut copy = mm.clone();
+        assert_eq!(copy, mm);
+        copy.markov_chain.get_mut(&vec!['b']).unwrap().insert('a', 3.0);
+        assert_ne!(copy, mm);
+        let unsmoothed = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        assert_ne!(unsmoothed, mm);
+        assert!(format!("{:?}", unsmoothed).contains("order: 2"));
+    }
+
+    #[test]
+    fn test_memory_usage_estimate() {
+        let empty = MultiMarkov::<char>::builder().build();
+        let small = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .without_prior()
+            .train(char_data())
+            .build();
+        let large = MultiMarkov::<char>::builder()
+            .with_order(3)
+            .train(char_data())
+            .build();
+        assert!(empty.memory_usage() >= std::mem::size_of::<MultiMarkov<char>>());
+        assert!(empty.memory_usage() < small.memory_usage());
+        assert!(small.memory_usage() < large.memory_usage());
+    }
+
+    #[test]
+    fn test_sample_n_distinct() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_seed(8)
+            .train(char_data())
+            .build();
+        for _ in 0..20 {
+            let sample = mm.sample_n_distinct(&['b','a'], 3);
+            assert_eq!(sample.len(), 3);
+            assert_eq!(sample.iter().collect::<HashSet<&char>>().len(), 3);
+        }
+        // without priors, only 'r' and 'z' can follow ['b','a']
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        let mut sample = mm.sample_n_distinct(&['b','a'], 5);
+        sample.sort();
+        assert_eq!(sample, vec!['r','z']);
+        assert!(mm.sample_n_distinct(&['z'], 2).is_empty());
+    }
+
+    #[test]
+    fn test_cross_entropy_handles_unknown_states() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .train(char_data())
+            .build();
+        let held_out = vec![vec!['b','a','r']];
+        let cross_entropy = mm.cross_entropy(&held_out, OovHandling::Error).unwrap();
+        assert!((cross_entropy - mm.perplexity(&held_out).ln()).abs() < 1e-12);
+        // 'x' was never seen, so both "b -> x" and "x -> a" are out of vocabulary
+        let with_oov = vec![vec!['b','a','r'], vec!['b','x','a']];
+        assert!(matches!(mm.cross_entropy(&with_oov, OovHandling::Error), Err(MultiMarkovError::UnknownState)));
+        assert_eq!(mm.cross_entropy(&with_oov, OovHandling::Skip).unwrap(), cross_entropy);
+        let floored = mm.cross_entropy(&with_oov, OovHandling::Floor(0.001)).unwrap();
+        let expected = (2.0 * cross_entropy - 2.0 * 0.001f64.ln()) / 4.0;
+        assert!((floored - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_next_rank() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        // 'o' was followed by 'o' and 'b', once each
+        let rank_of_b = mm.next_rank(&['o'], &'b').unwrap();
+        assert!(rank_of_b == 1 || rank_of_b == 2);
+        assert_eq!(mm.top_k_next(&['o'], rank_of_b).last().unwrap().0, 'b');
+        assert_eq!(mm.next_rank(&['o'], &'r'), None);
+        assert_eq!(mm.next_rank(&['z'], &'a'), None);
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .train(char_data())
+            .build();
+        // 'a' was followed by 'c' once, 'r' once and 'z' once; unobserved states rank below them
+        assert!(mm.next_rank(&['a'], &'z').unwrap() <= 3);
+        assert!(mm.next_rank(&['a'], &'o').unwrap() > 3);
+    }
+
+    #[test]
+    fn test_known_state_helpers() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .train(char_data())
+            .build();
+        assert_eq!(mm.known_state_count(), 7);
+        assert!(mm.is_known_state(&'z'));
+        // 'f' only ever began a sequence, so it never followed anything
+        assert!(!mm.is_known_state(&'f'));
+        assert!(!mm.is_known_state(&'x'));
+    }
+
+    #[test]
+    fn test_to_tsv() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .without_prior()
+            .train(char_data())
+            .build();
+        assert_eq!(mm.to_tsv(), "a\tc\t1\na\tr\t1\na\tz\t1\nb\ta\t2\nc\te\t1\nf\to\t1\no\tb\t1\no\to\t1\n");
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        assert!(mm.to_tsv().contains("b a\tr\t1\n"));
+    }
+
+    #[test]
+    fn test_bigram_coverage() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        // of the 7 known states, 'a', 'b', 'c' and 'o' were followed by 3, 1, 1 and 2 of them
+        assert!((mm.bigram_coverage() - 7.0 / 49.0).abs() < 1e-12);
+        let smoothed = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .train(char_data())
+            .build();
+        assert!(smoothed.bigram_coverage() > mm.bigram_coverage());
+        assert_eq!(MultiMarkov::<char>::builder().build().bigram_coverage(), 0.0);
+    }
+
+    #[test]
+    fn test_adaptive_interpolated_distribution() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        // ['a'] gives 'c', 'r' and 'z' 1/3 each; ['b','a'] gives 'r' and 'z' 1/2 each, with weight 2/(2+2)
+        let blended = mm.adaptive_interpolated_distribution(&['b','a']).unwrap();
+        assert!((blended[&'r'] - 5.0 / 12.0).abs() < 1e-12);
+        assert!((blended[&'z'] - 5.0 / 12.0).abs() < 1e-12);
+        assert!((blended[&'c'] - 1.0 / 6.0).abs() < 1e-12);
+        // only ['o'] matches, so its distribution is used as it is
+        assert_eq!(mm.adaptive_interpolated_distribution(&['x','o']), mm.next_distribution(&['o']));
+        assert_eq!(mm.adaptive_interpolated_distribution(&['z']), None);
+    }
+
+    #[test]
+    fn test_random_next_transformed() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_seed(12)
+            .train(char_data())
+            .build();
+        // boosting 'z' makes it by far the likeliest state to follow ['b','a']
+        let draws = (0..200).filter(|_| mm.random_next_transformed(&['b','a'], |state, weight| if *state == 'z' { weight * 1000.0 } else { weight }) == Some('z')).count();
+        assert!(draws > 190);
+        assert_eq!(mm.random_next_transformed(&['b','a'], |_, _| 0.0), None);
+        assert_eq!(mm.random_next_transformed(&['b','a'], |_, _| f64::NAN), None);
+    }
+
+    #[test]
+    fn test_non_initial_states() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .train(char_data())
+            .build();
+        // 'a' and 'b' begin training sequences ('f' does too, but never follows anything)
+        let expected: HashSet<char> = vec!['c','e','o','r','z'].into_iter().collect();
+        assert_eq!(mm.non_initial_states(), expected);
+    }
+
+    #[test]
+    fn test_classify() {
+        let first = MultiMarkov::<char>::builder().with_order(1).without_prior().train(char_data()).build();
+        let second = MultiMarkov::<char>::builder().with_order(1).without_prior().train(vec![vec!['z','a','b']]).build();
+        let models = [("first", &first), ("second", &second)];
+        assert_eq!(MultiMarkov::classify(&models, &['b','a','r']), Some("first"));
+        assert_eq!(MultiMarkov::classify(&models, &['z','a','b']), Some("second"));
+        // impossible under both models, so the first wins the tie
+        assert_eq!(MultiMarkov::classify(&models, &['x','y']), Some("first"));
+        assert_eq!(MultiMarkov::<char>::classify(&[], &['a','b']), None);
+    }
+
+    #[test]
+    fn test_max_context_caps_lookups() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        assert_eq!(mm.max_context(), 2);
+        assert_eq!(mm.next_distribution(&['b','a']).unwrap().len(), 2);
+        mm.set_max_context(1).unwrap();
+        assert_eq!(mm.next_distribution(&['b','a']), mm.next_distribution(&['a']));
+        assert_eq!(mm.next_distribution(&['b','a']).unwrap().len(), 3);
+        assert!(matches!(mm.set_max_context(3), Err(MultiMarkovError::ContextTooLong(3, 2))));
+        assert!(matches!(mm.set_max_context(-1), Err(MultiMarkovError::InvalidOrder(-1))));
+        assert_eq!(mm.max_context(), 1);
+    }
+
+    #[test]
+    fn test_stable_order_reproduces_draws() {
+        // every model has its own HashMap iteration order, even within one run
+        let draws = || {
+            let mut mm = MultiMarkov::<char>::builder()
+                .with_order(2)
+                .with_seed(21)
+                .with_stable_order()
+                .train(char_data())
+                .build();
+            let draws: Vec<Option<char>> = (0..200).map(|_| mm.random_next(&['b','a'])).collect();
+            let generated: Vec<Vec<char>> = (0..20).map(|_| mm.generate_sequence(&['b'])).collect();
+            let mut lazy = MultiMarkov::<char>::builder()
+                .with_order(2)
+                .with_seed(21)
+                .with_lazy_prior(0.1)
+                .train(char_data())
+                .build();
+            let lazy_draws: Vec<Option<char>> = (0..200).map(|_| lazy.random_next(&['b','a'])).collect();
+            (draws, generated, mm.top_k_next(&['x'], 3), lazy_draws)
+        };
+        let first = draws();
+        for _ in 0..5 {
+            assert_eq!(draws(), first);
+        }
+    }
+
+    #[test]
+    fn test_stable_hash_is_fnv1a() {
+        // the published FNV-1a test vectors
+        let mut hasher = Fnv1a::default();
+        assert_eq!(hasher.finish(), 0xcbf29ce484222325);
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63dc4c8601ec8c);
+        let mut hasher = Fnv1a::default();
+        hasher.write(b"foobar");
+        assert_eq!(hasher.finish(), 0x85944171f73967e8);
+        // integers are hashed as little-endian bytes
+        let mut hasher = Fnv1a::default();
+        hasher.write_u32(u32::from_le_bytes(*b"foob"));
+        hasher.write_u16(u16::from_le_bytes(*b"ar"));
+        assert_eq!(hasher.finish(), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn test_context_entropy() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .without_prior()
+            .train(vec![vec!['a','b','a','c']])
+            .build();
+        // 'a' is followed by 'b' and 'c' equally often; 'b' only ever by 'a'
+        assert!((mm.context_entropy(&['a']).unwrap() - 2f64.ln()).abs() < 1e-12);
+        assert_eq!(mm.context_entropy(&['b']), Some(0.0));
+        assert_eq!(mm.context_entropy(&['z']), None);
+        // contexts are weighted by how often they were observed: 'a' twice, 'b' once
+        let expected = 2.0 * 2f64.ln() / 3.0;
+        assert!((mm.mean_entropy() - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_mean_entropy_weights_by_smoothed_totals() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .with_lazy_prior(0.5)
+            .train(vec![vec!['a','b','a','c']])
+            .build();
+        // 'a' -> {b: 1, c: 1, a: 0.5} totals 2.5; 'b' -> {a: 1, b: 0.5, c: 0.5} totals 2
+        let a_entropy = entropy([0.4, 0.4, 0.2].iter());
+        let b_entropy = entropy([0.5, 0.25, 0.25].iter());
+        assert!((mm.context_entropy(&['a']).unwrap() - a_entropy).abs() < 1e-12);
+        let expected = (2.5 * a_entropy + 2.0 * b_entropy) / 4.5;
+        assert!((mm.mean_entropy() - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_random_next_batch_keeps_input_order() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        let contexts = vec![vec!['a','c'], vec!['z'], vec!['o','b'], vec!['f']];
+        assert_eq!(mm.random_next_batch(&contexts), vec![Some('e'), None, Some('a'), Some('o')]);
+    }
+
+    #[test]
+    fn test_order_histogram() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(3)
+            .train(char_data())
+            .build();
+        // the contexts followed by something are a, c, f, o, b; ac, fo, oo, ob, ba; foo, oob, oba
+        assert_eq!(mm.order_histogram(), vec![(1, 5), (2, 5), (3, 3)]);
+    }
+
+    #[test]
+    fn test_order_histogram_skips_contexts_longer_than_the_order() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .train(char_data())
+            .build();
+        let before = mm.order_histogram();
+        mm.markov_chain.insert(vec!['f','o'], vec![('o', 1.0)].into_iter().collect());
+        assert_eq!(mm.order_histogram(), before);
+    }
+
+    #[test]
+    fn test_strict_states() {
+        let mut strict = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .with_strict_states()
+            .train(char_data())
+            .build();
+        // 'f' only ever starts a sequence, but was still seen in training
+        assert_eq!(strict.try_random_next(&['f']).unwrap(), Some('o'));
+        assert!(matches!(strict.try_random_next(&['x','f']), Err(MultiMarkovError::UnknownState)));
+        assert!(matches!(strict.try_transition_probability(&['a'], &'x'), Err(MultiMarkovError::UnknownState)));
+        let lenient = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        assert_eq!(lenient.try_transition_probability(&['x','f'], &'o').unwrap(), 1.0);
+    }
+
+    #[test]
+    fn test_backoff_penalty() {
+        let build = |penalty| MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_prior(0.01)
+            .with_backoff_penalty(penalty)
+            .train(char_data())
+            .build();
+        let (unpenalized, penalized) = (build(1.0), build(0.4));
+        // ['a','c'] matched at full order, so nothing changes
+        assert!((penalized.sequence_log_probability(&['a','c','e']) - unpenalized.sequence_log_probability(&['a','c','e'])).abs() < 1e-12);
+        // ['o','c'] was never seen, so 'e' is scored after backing off one state to ['c']
+        let backed_off = unpenalized.sequence_log_probability(&['o','c','e']) + 0.4f64.ln();
+        assert!((penalized.sequence_log_probability(&['o','c','e']) - backed_off).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_matching_models_longest_first() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(3)
+            .without_prior()
+            .train(char_data())
+            .build();
+        // ['x','b','a'] was never seen, but ['b','a'] and ['a'] were
+        let contexts: Vec<&[char]> = mm.matching_models(&['x','b','a']).map(|(context, _)| context).collect();
+        assert_eq!(contexts, vec![&['b','a'][..], &['a'][..]]);
+        // the first is the one `random_next` draws from
+        let (_, best) = mm.matching_models(&['x','b','a']).next().unwrap();
+        assert_eq!(best.keys().collect::<HashSet<_>>(), [&'r', &'z'].into_iter().collect());
+    }
+
+    #[test]
+    fn test_draw_survives_rounding() {
+        let weights = [('a', 0.3), ('b', 0.1), ('c', 0.45)];
+        let sum_of_weights: f64 = weights.iter().map(|(_, v)| v).sum();
+        // the largest roll below 1: subtracting 0.3 and 0.1 leaves slightly more than 0.45
+        let r = 1.0 - f64::EPSILON / 2.0;
+        assert_eq!(MultiMarkov::<char>::draw(r, weights.iter().map(|(k, v)| (k, *v)), sum_of_weights), Some('c'));
+    }
+
+    #[test]
+    fn test_arrays_without_allocating() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train([['b','a','z'], ['b','a','r']])
+            .build();
+        let drawn = mm.random_next_batch(&[['b','a'], ['x','z']]);
+        assert!(matches!(drawn[0], Some('r') | Some('z')));
+        assert_eq!(drawn[1], None);
+        let arrays = [['b','a','z']];
+        let vectors = vec![vec!['b','a','z']];
+        assert_eq!(mm.perplexity(&arrays), mm.perplexity(&vectors));
+        assert_eq!(mm.cross_entropy(&arrays, OovHandling::Error).unwrap(), mm.cross_entropy(&vectors, OovHandling::Error).unwrap());
+    }
+
+    #[test]
+    fn test_model_is_send_and_sync() {
+        fn assert_send_sync<S: Send + Sync>() {}
+        assert_send_sync::<MultiMarkov<char>>();
+        assert_send_sync::<MultiMarkov<String>>();
+        assert_send_sync::<MultiMarkovBuilder<char>>();
+    }
+
+    #[test]
+    fn test_concurrent_generation_from_shared_model() {
+        let mm = std::sync::Arc::new(MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build());
+        let handles: Vec<_> = (0..4).map(|i| {
+            let mm = std::sync::Arc::clone(&mm);
+            std::thread::spawn(move || {
+                let mut rng = StdRng::seed_from_u64(i);
+                (0..200).map(|_| mm.random_next_with(&['b','a'], &mut rng).unwrap()).collect::<Vec<char>>()
+            })
+        }).collect();
+        for handle in handles {
+            let drawn = handle.join().unwrap();
+            assert_eq!(drawn.len(), 200);
+            assert!(drawn.iter().all(|&next| next == 'r' || next == 'z'));
+        }
+    }
+
+}
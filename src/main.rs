@@ -1,5 +1,3 @@
-mod builder;
-
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use multimarkov::MultiMarkov;
@@ -8,27 +6,20 @@ fn main() {
 
     let file = File::open("resources/romans.txt").unwrap();
     let reader = BufReader::new(file);
-    let lines = reader.lines().map(|l| l.unwrap().to_lowercase() ).map(|l| l.chars().collect::<Vec<_>>()).map(|mut v| { v.insert(0, '#'); v.push('#'); v });
+    let lines = reader.lines().map(|l| l.unwrap().to_lowercase() ).map(|l| l.chars().collect::<Vec<_>>());
 
-    let mm = MultiMarkov::<char>::builder()
+    let mut mm = MultiMarkov::<char>::builder()
         .with_order(3)
         .with_prior(0.02)
+        .with_sentinel('#') // the beginning-of-word and end-of-word character
         .train(lines)
         .build();
 
     for _i in 0..10 {
         // generate a roman-sounding name
-        let mut name = vec!['#']; // the beginning-of-word and end-of-word character
-        name.push(mm.random_next(&name).unwrap());
-        while !name.ends_with(&*vec!['#']) {
-            name.push(mm.random_next(&name).unwrap());
-        }
-        name.pop();
-        name.remove(0);
+        let name = mm.generate_sequence(&[]);
         let stringname = name.iter().collect::<String>();
         println!("{}", stringname);
     }
 
 }
-
-
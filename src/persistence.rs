@@ -0,0 +1,120 @@
+use std::fs::File;
+use std::hash::Hash;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::{MultiMarkov, Weight};
+use crate::error::MultiMarkovError;
+
+/// Identifies a file as a saved MultiMarkov model.
+const MAGIC: &[u8; 4] = b"MMKV";
+
+/// Bump this whenever the serialized layout of `MultiMarkov` changes, so that older files are
+/// rejected with a clear error instead of being deserialized into garbage.
+const FORMAT_VERSION: u32 = 16;
+
+impl<T, W> MultiMarkov<T, W>
+    where T: Eq + Hash + Clone + Serialize + DeserializeOwned, W: Weight + Serialize + DeserializeOwned
+{
+    /// Save the model to a compact binary file using bincode, so that it can be loaded later with
+    /// `load_from_file` instead of being trained again.
+    pub fn save_to_file(&self, path: impl AsRef<Path>) -> Result<(), MultiMarkovError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&[std::mem::size_of::<W>() as u8])?;
+        bincode::serialize_into(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Load a model previously saved with `save_to_file`.  Returns an `Err` if the file can't be
+    /// read, isn't a saved model, or was saved in an incompatible format version or with a
+    /// different weight type (e.g. saved from an `f64` model and loaded as an `f32` one).
+    ///
+    /// As with any deserialized model, the random number generator is seeded from `rand::thread_rng()`.
+    /// The cached weight sums, which aren't saved, are recomputed.
+    pub fn load_from_file(path: impl AsRef<Path>) -> Result<Self, MultiMarkovError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).map_err(|_| MultiMarkovError::NotAModelFile)?;
+        if &magic != MAGIC {
+            return Err(MultiMarkovError::NotAModelFile);
+        }
+        let mut version = [0u8; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != FORMAT_VERSION {
+            return Err(MultiMarkovError::UnsupportedFormatVersion(version));
+        }
+        let mut weight_size = [0u8; 1];
+        reader.read_exact(&mut weight_size)?;
+        if weight_size[0] as usize != std::mem::size_of::<W>() {
+            return Err(MultiMarkovError::WeightTypeMismatch(weight_size[0] as usize));
+        }
+        let mut model: Self = bincode::deserialize_from(reader)?;
+        model.refresh_weight_sums();
+        Ok(model)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn char_data() -> Vec<Vec<char>> {
+        vec![
+            vec!['a','c','e'],
+            vec!['f','o','o','b','a','r'],
+            vec!['b','a','z'],
+        ]
+    }
+
+    #[test]
+    fn test_save_and_load_round_trip() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_prior(0.001)
+            .train(char_data())
+            .build();
+        let path = std::env::temp_dir().join("multimarkov_test_save_and_load_round_trip.bin");
+        mm.save_to_file(&path).unwrap();
+        let loaded = MultiMarkov::<char>::load_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.order(), mm.order());
+        assert_eq!(loaded.markov_chain, mm.markov_chain);
+        assert_eq!(loaded.known_states, mm.known_states);
+        assert_eq!(loaded.weight_sums.len(), mm.weight_sums.len());
+    }
+
+    #[test]
+    fn test_save_and_load_f32_weights() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .train(char_data())
+            .build_with_weights::<f32>();
+        let path = std::env::temp_dir().join("multimarkov_test_save_and_load_f32_weights.bin");
+        mm.save_to_file(&path).unwrap();
+        let loaded = MultiMarkov::<char, f32>::load_from_file(&path).unwrap();
+        let mismatched = MultiMarkov::<char>::load_from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.markov_chain, mm.markov_chain);
+        assert!(matches!(mismatched, Err(MultiMarkovError::WeightTypeMismatch(4))));
+    }
+
+    #[test]
+    fn test_load_rejects_incompatible_files() {
+        let path = std::env::temp_dir().join("multimarkov_test_load_rejects_incompatible_files.bin");
+        std::fs::write(&path, b"not a model").unwrap();
+        assert!(matches!(MultiMarkov::<char>::load_from_file(&path), Err(MultiMarkovError::NotAModelFile)));
+        let mut wrong_version = MAGIC.to_vec();
+        wrong_version.extend_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        std::fs::write(&path, wrong_version).unwrap();
+        let result = MultiMarkov::<char>::load_from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(MultiMarkovError::UnsupportedFormatVersion(version)) if version == FORMAT_VERSION + 1));
+    }
+
+}
@@ -0,0 +1,144 @@
+use std::hash::Hash;
+use rand::Rng;
+use rand::rngs::StdRng;
+use crate::{MultiMarkov, StateMap, Weight, tails};
+
+/// A snapshot of a `MultiMarkov` model's transitions, precomputed into Walker's alias tables so
+/// that each draw takes constant time however many states can follow a context.  Create one with
+/// `MultiMarkov::build_sampler` once training is complete.
+///
+/// Draws follow the same distributions as `MultiMarkov::random_next`, using the same best-fitted
+/// model for each sequence.  The sampler is a copy: it does not see later changes to the model it
+/// was built from, so build a new one after modifying the model.  Its tables hold probabilities
+/// in the model's weight type.
+pub struct AliasSampler<T, W = f64>
+    where T: Eq + Hash + Clone, W: Weight
+{
+    tables: StateMap<Vec<T>, AliasTable<T, W>>,
+    max_context: i32,
+    rng: StdRng,
+}
+
+impl<T, W> AliasSampler<T, W>
+    where T: Eq + Hash + Clone, W: Weight
+{
+    pub(crate) fn new(model: &MultiMarkov<T, W>, rng: StdRng) -> Self {
+        let tables = model.markov_chain.iter()
+            .filter_map(|(context, transitions)| Some((context.clone(), AliasTable::new(model.candidates(context, transitions))?)))
+            .collect();
+        Self {
+            tables,
+            max_context: model.max_context,
+            rng,
+        }
+    }
+
+    /// Draw a new state to follow the given sequence, like `MultiMarkov::random_next`.
+    pub fn next(&mut self, current_sequence: &[T]) -> Option<T> {
+        // same search as `MultiMarkov::matching_models`: the longest tail of the sequence with a table
+        let table = tails(current_sequence, self.max_context).find_map(|tail| self.tables.get(tail))?;
+        Some(table.draw(&mut self.rng).clone())
+    }
+}
+
+/// The alias table for one context's following states.  Each column `i` holds state `i` with
+/// probability `probabilities[i]`, and otherwise its alias, state `aliases[i]`.
+struct AliasTable<T, W> {
+    states: Vec<T>,
+    probabilities: Vec<W>,
+    aliases: Vec<usize>,
+}
+
+impl<T, W> AliasTable<T, W>
+    where T: Clone, W: Weight
+{
+    /// Build a table with Vose's algorithm from a context's (state, weight) pairs.  Returns `None`
+    /// if no state has a positive weight.
+    fn new(transitions: Vec<(&T,W)>) -> Option<Self> {
+        let (states, weights): (Vec<T>, Vec<W>) = transitions.into_iter()
+            .filter(|(_, weight)| *weight > W::ZERO)
+            .map(|(state, weight)| (state.clone(), weight))
+            .unzip();
+        if states.is_empty() { return None; }
+        let n = states.len();
+        let sum_of_weights: W = weights.iter().sum();
+        // scale so that the average column holds exactly 1
+        let one = W::from_f64(1.0);
+        let mut scaled: Vec<W> = weights.iter().map(|weight| *weight * W::from_f64(n as f64) / sum_of_weights).collect();
+        let mut probabilities = vec![one; n];
+        let mut aliases: Vec<usize> = (0..n).collect();
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) = (0..n).partition(|&i| scaled[i] < one);
+        while let (Some(&less), Some(&more)) = (small.last(), large.last()) {
+            small.pop();
+            probabilities[less] = scaled[less];
+            aliases[less] = more;
+            let left_over = scaled[less] - one;
+            scaled[more] += left_over;
+            if scaled[more] < one {
+                large.pop();
+                small.push(more);
+            }
+        }
+        // whatever is left over is 1 up to rounding error, so those columns keep their own state
+        Some(Self { states, probabilities, aliases })
+    }
+
+    fn draw(&self, rng: &mut impl Rng) -> &T {
+        let column = rng.gen_range(0, self.states.len());
+        if W::from_f64(rng.gen()) < self.probabilities[column] {
+            &self.states[column]
+        } else {
+            &self.states[self.aliases[column]]
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn char_data() -> Vec<Vec<char>> {
+        vec![
+            vec!['a','c','e'],
+            vec!['f','o','o','b','a','r'],
+            vec!['b','a','z'],
+        ]
+    }
+
+    #[test]
+    fn test_alias_sampler_matches_model_distribution() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_prior(0.5)
+            .with_seed(11)
+            .train(char_data())
+            .build();
+        let mut sampler = mm.build_sampler();
+        let expected = mm.next_distribution(&['o','b']).unwrap();
+        let draws = 20000;
+        let mut counts: HashMap<char,usize> = HashMap::new();
+        for _ in 0..draws {
+            *counts.entry(sampler.next(&['f','o','b']).unwrap()).or_insert(0) += 1;
+        }
+        for (state, probability) in expected {
+            let observed = *counts.get(&state).unwrap_or(&0) as f64 / draws as f64;
+            assert!((observed - probability).abs() < 0.02, "{} drawn with frequency {}, expected {}", state, observed, probability);
+        }
+    }
+
+    #[test]
+    fn test_alias_sampler_falls_back_like_random_next() {
+        let mut mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .without_prior()
+            .train(char_data())
+            .build();
+        let mut sampler = mm.build_sampler();
+        // ['x','c'] is unseen, so the model for ['c'] is used, and only 'e' can follow it
+        assert_eq!(sampler.next(&['x','c']), Some('e'));
+        assert_eq!(sampler.next(&['z']), None);
+    }
+
+}
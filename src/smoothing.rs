@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use crate::{StateMap, StateSet};
+use crate::error::MultiMarkovError;
+
+/// A smoothing strategy: a way of turning the raw transition counts learned in training into the
+/// weights the model draws from, typically giving some weight to transitions never observed.
+/// Pass an implementation to `MultiMarkovBuilder::with_smoothing` to use it; it is applied
+/// once, when the model is built.
+///
+/// `markov_chain` holds the counts for every context of every order, as in
+/// `MultiMarkov::markov_chain`, and `known_states` is every state seen in training.  The weights
+/// left in `markov_chain` need not sum to 1, but they must not be negative.  Write their types with
+/// the `StateMap` and `StateSet` aliases, so that the implementation compiles whether or not the
+/// `fast-hash` feature is enabled.
+pub trait Smoothing<T>
+    where T: Eq + Hash + Clone
+{
+    /// Smooth the model in place.  `markov_chain` comes straight from training: it holds the raw
+    /// (possibly weighted) counts, with no prior or other smoothing applied yet, and only the
+    /// transitions that were observed.  The strategy may change any weight, add transitions to
+    /// known states and remove transitions, but it should not add or remove contexts, since
+    /// lookups back off according to which contexts exist.
+    fn smooth(&self, markov_chain: &mut StateMap<Vec<T>,StateMap<T,f64>>, known_states: &StateSet<T>);
+
+    /// Check, before `smooth` is called with the same raw counts, that the strategy can be applied
+    /// to them, so that `MultiMarkovBuilder::try_build` can return an `Err` instead of smoothing
+    /// into a broken model.  The default accepts anything.
+    fn validate(&self, _markov_chain: &StateMap<Vec<T>,StateMap<T,f64>>) -> Result<(),MultiMarkovError> {
+        Ok(())
+    }
+}
+
+/// A flat "prior": every known state that was never observed following a context is given this
+/// weight in that context.  This is what `MultiMarkovBuilder::with_prior` uses.
+pub struct FlatPrior(pub f64);
+
+impl<T> Smoothing<T> for FlatPrior
+    where T: Eq + Hash + Clone
+{
+    fn smooth(&self, markov_chain: &mut StateMap<Vec<T>,StateMap<T,f64>>, known_states: &StateSet<T>) {
+        PriorMap { priors: HashMap::new(), default: self.0 }.smooth(markov_chain, known_states);
+    }
+}
+
+/// A flat prior chosen from the data instead of given: the smallest observed count divided by
+/// the number of known states.  With whole-number counts that is `1 / known_states.len()`, so in
+/// each context all the unseen states together weigh no more than a single observation.  This is
+/// what `MultiMarkovBuilder::with_auto_prior` uses.
+pub struct AutoPrior;
+
+impl<T> Smoothing<T> for AutoPrior
+    where T: Eq + Hash + Clone
+{
+    fn smooth(&self, markov_chain: &mut StateMap<Vec<T>,StateMap<T,f64>>, known_states: &StateSet<T>) {
+        let smallest_count = markov_chain.values().flat_map(|transitions| transitions.values()).cloned().fold(f64::INFINITY, f64::min);
+        if known_states.is_empty() || !smallest_count.is_finite() { return; }
+        FlatPrior(smallest_count / known_states.len() as f64).smooth(markov_chain, known_states);
+    }
+}
+
+/// Like `FlatPrior`, but with a different prior for each state; states missing from `priors` get
+/// the `default` prior.  A prior of 0.0 means the state is never added to a context it wasn't
+/// observed following.  This is what `MultiMarkovBuilder::with_prior_map` uses.
+pub struct PriorMap<T> {
+    pub priors: HashMap<T,f64>,
+    pub default: f64,
+}
+
+impl<T> Smoothing<T> for PriorMap<T>
+    where T: Eq + Hash + Clone
+{
+    fn smooth(&self, markov_chain: &mut StateMap<Vec<T>,StateMap<T,f64>>, known_states: &StateSet<T>) {
+        for v in markov_chain.values_mut() {
+            for a in known_states.iter() {
+                let p = self.priors.get(a).copied().unwrap_or(self.default);
+                if p > 0.0 {
+                    v.entry(a.clone()).or_insert(p);
+                }
+            }
+        }
+    }
+}
+
+/// Absolute discounting: the discount is subtracted from every observed count, and the freed
+/// weight is shared equally between the known states never observed following that context.
+/// Contexts that were observed followed by every known state are left as they are.  This is
+/// what `MultiMarkovBuilder::with_absolute_discounting` uses.
+///
+/// The discount must be smaller than the smallest observed count, which would otherwise be left
+/// with no weight or negative weight; `validate` returns `MultiMarkovError::InvalidDiscount` if not.
+pub struct AbsoluteDiscount(pub f64);
+
+impl<T> Smoothing<T> for AbsoluteDiscount
+    where T: Eq + Hash + Clone
+{
+    fn smooth(&self, markov_chain: &mut StateMap<Vec<T>,StateMap<T,f64>>, known_states: &StateSet<T>) {
+        let discount = self.0;
+        for transitions in markov_chain.values_mut() {
+            let unseen: Vec<&T> = known_states.iter().filter(|state| !transitions.contains_key(*state)).collect();
+            if unseen.is_empty() { continue; }
+            let share = discount * transitions.len() as f64 / unseen.len() as f64;
+            for weight in transitions.values_mut() {
+                *weight -= discount;
+            }
+            for state in unseen {
+                transitions.insert(state.clone(), share);
+            }
+        }
+    }
+
+    fn validate(&self, markov_chain: &StateMap<Vec<T>,StateMap<T,f64>>) -> Result<(),MultiMarkovError> {
+        let smallest_count = markov_chain.values().flat_map(|transitions| transitions.values()).cloned().fold(f64::INFINITY, f64::min);
+        if self.0 < smallest_count { Ok(()) } else { Err(MultiMarkovError::InvalidDiscount(self.0)) }
+    }
+}
+
+/// Interpolated Kneser-Ney smoothing: the `discount` is subtracted from every observed count, and
+/// the freed weight is redistributed over all known states according to lower-order
+/// "continuation" probabilities, so a state gets more weight if it has been seen following many
+/// *different* contexts, rather than merely often.  Each context's weights are then renormalized
+/// to sum to 1, since counts smaller than the discount free less than the full discount.  This is
+/// what `MultiMarkovBuilder::with_kneser_ney` uses.
+pub struct KneserNey {
+    pub discount: f64,
+}
+
+impl KneserNey {
+    /// The Kneser-Ney probability of `next` following the lower-order `context`, based on
+    /// continuation counts, recursively interpolated down to a uniform distribution over known states.
+    fn continuation_probability<T>(&self, continuations: &StateMap<Vec<T>,StateMap<T,f64>>, context: &[T], next: &T, known_states: &StateSet<T>) -> f64
+        where T: Eq + Hash + Clone
+    {
+        let lower_order = if context.is_empty() {
+            1.0 / known_states.len() as f64
+        } else {
+            self.continuation_probability(continuations, &context[1..], next, known_states)
+        };
+        match continuations.get(context) {
+            Some(counts) => {
+                let total: f64 = counts.values().sum();
+                let count = counts.get(next).copied().unwrap_or(0.0);
+                (count - self.discount).max(0.0) / total + self.discount * counts.len() as f64 / total * lower_order
+            },
+            None => lower_order,
+        }
+    }
+}
+
+impl<T> Smoothing<T> for KneserNey
+    where T: Eq + Hash + Clone
+{
+    fn smooth(&self, markov_chain: &mut StateMap<Vec<T>,StateMap<T,f64>>, known_states: &StateSet<T>) {
+        // Continuation counts: for each context, the number of distinct states observed directly
+        // before it (i.e. as the first state of a one-longer context) with each following state.
+        let mut continuations: StateMap<Vec<T>,StateMap<T,f64>> = StateMap::default();
+        for (context, transitions) in markov_chain.iter().filter(|(context, _)| !context.is_empty()) {
+            let counts = continuations.entry(Vec::from(&context[1..])).or_default();
+            for next in transitions.keys() {
+                *counts.entry(next.clone()).or_insert(0.0) += 1.0;
+            }
+        }
+        for (context, transitions) in markov_chain.iter_mut() {
+            let total: f64 = transitions.values().sum();
+            let reserved = self.discount * transitions.len() as f64 / total;
+            let smoothed: HashMap<T,f64> = known_states.iter().map(|next| {
+                let count = transitions.get(next).copied().unwrap_or(0.0);
+                let lower_order = if context.is_empty() {
+                    1.0 / known_states.len() as f64
+                } else {
+                    self.continuation_probability(&continuations, &context[1..], next, known_states)
+                };
+                let weight = (count - self.discount).max(0.0) / total + reserved * lower_order;
+                (next.clone(), weight)
+            }).collect();
+            let sum_of_weights: f64 = smoothed.values().sum();
+            *transitions = smoothed.into_iter().map(|(next, weight)| (next, weight / sum_of_weights)).collect();
+        }
+    }
+}
+
+/// Witten-Bell smoothing: each context reserves a share of its probability for the known states
+/// never observed following it, in proportion to the number of *distinct* states that were
+/// observed following it.  A context with `n` observations of `t` distinct following states gives
+/// each observed state `count / (n + t)`, and shares `t / (n + t)` equally between the unseen
+/// ones, so contexts that have been followed by many different states expect more novelty.
+/// Contexts that were observed followed by every known state are left as they are.  This is what
+/// `MultiMarkovBuilder::with_witten_bell` uses.
+pub struct WittenBell;
+
+impl<T> Smoothing<T> for WittenBell
+    where T: Eq + Hash + Clone
+{
+    fn smooth(&self, markov_chain: &mut StateMap<Vec<T>,StateMap<T,f64>>, known_states: &StateSet<T>) {
+        for transitions in markov_chain.values_mut() {
+            let unseen: Vec<&T> = known_states.iter().filter(|state| !transitions.contains_key(*state)).collect();
+            if unseen.is_empty() { continue; }
+            let total: f64 = transitions.values().sum();
+            let distinct = transitions.len() as f64;
+            let share = distinct / (total + distinct) / unseen.len() as f64;
+            for weight in transitions.values_mut() {
+                *weight /= total + distinct;
+            }
+            for state in unseen {
+                transitions.insert(state.clone(), share);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MultiMarkov;
+
+    /// Gives every context a uniform distribution over all known states.
+    struct Uniform;
+
+    impl Smoothing<char> for Uniform {
+        fn smooth(&self, markov_chain: &mut StateMap<Vec<char>,StateMap<char,f64>>, known_states: &StateSet<char>) {
+            for transitions in markov_chain.values_mut() {
+                *transitions = known_states.iter().map(|state| (*state, 1.0)).collect();
+            }
+        }
+    }
+
+    #[test]
+    fn test_custom_smoothing() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(2)
+            .with_smoothing(Uniform)
+            .train(vec![vec!['f','o','o','b','a','r']])
+            .build();
+        let after_fo = mm.next_distribution(&['f','o']).unwrap();
+        assert_eq!(after_fo.len(), mm.known_states.len());
+        assert!(after_fo.values().all(|p| (p - 0.25).abs() < 1e-12));
+    }
+
+    #[test]
+    fn test_auto_prior_scales_with_known_states() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .with_auto_prior()
+            .train(vec![vec!['a','b','a','c','d']])
+            .build();
+        // four known states, and the smallest count is 1
+        assert_eq!(mm.markov_chain[&vec!['a']][&'d'], 0.25);
+        assert_eq!(mm.markov_chain[&vec!['a']][&'b'], 1.0);
+    }
+
+    #[test]
+    fn test_kneser_ney_is_a_smoothing_strategy() {
+        let data = vec![vec!['f','o','o','b','a','r'], vec!['b','a','z']];
+        let builtin = MultiMarkov::<char>::builder().with_order(2).with_kneser_ney(0.75).train(data.clone()).build();
+        let custom = MultiMarkov::<char>::builder().with_order(2).with_smoothing(KneserNey { discount: 0.75 }).train(data).build();
+        for (context, transitions) in builtin.markov_chain.iter() {
+            for (next, weight) in transitions {
+                assert!((custom.markov_chain[context][next] - weight).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_kneser_ney_sums_to_one_below_the_discount() {
+        // counts of 0.5 are smaller than the discount, so subtracting it frees only 0.5 of each
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .with_kneser_ney(0.75)
+            .train_weighted(vec![(vec!['a','b','a','c'], 0.5), (vec!['c','a'], 2.0)])
+            .build();
+        for transitions in mm.markov_chain.values() {
+            assert!((transitions.values().sum::<f64>() - 1.0).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_witten_bell_reserves_more_for_varied_contexts() {
+        let mm = MultiMarkov::<char>::builder()
+            .with_order(1)
+            .with_witten_bell()
+            .train(vec![vec!['a','b','a','b','a','c']])
+            .build();
+        // 'a' was followed by 'b' twice and 'c' once: two distinct states in three observations
+        let after_a = mm.next_distribution(&['a']).unwrap();
+        assert!((after_a[&'b'] - 0.4).abs() < 1e-12);
+        assert!((after_a[&'c'] - 0.2).abs() < 1e-12);
+        assert!((after_a[&'a'] - 0.4).abs() < 1e-12);
+        // 'b' was only ever followed by 'a', so less is reserved for 'b' and 'c'
+        let after_b = mm.next_distribution(&['b']).unwrap();
+        assert!((after_b[&'a'] - 2.0 / 3.0).abs() < 1e-12);
+        assert!((after_b[&'b'] - 1.0 / 6.0).abs() < 1e-12);
+        assert!((after_b[&'c'] - 1.0 / 6.0).abs() < 1e-12);
+    }
+
+}
@@ -0,0 +1,50 @@
+use std::fmt::{Debug, Display};
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Sub, SubAssign};
+
+/// The floating point type a built `MultiMarkov` stores its weights in: `f64` (the default), or
+/// `f32` to halve the memory taken by the weights of a large model.  Build an `f32` model with
+/// `MultiMarkovBuilder::build_with_weights::<f32>()`.  Sampling, normalization and a lazy prior
+/// work in the weight type, while the probabilities and scores the model returns are always `f64`.
+///
+/// The builder counts in `f64` whatever the weight type, and converts the counts when the model is
+/// built, so training never loses precision.  An `f32` holds every whole count up to 2^24
+/// (16,777,216) exactly; larger counts are rounded to about 7 significant digits, which barely
+/// changes relative weights.  Counts above about 3.4e38 don't fit at all, and building returns
+/// `MultiMarkovError::WeightOverflow`.
+pub trait Weight: Copy + Debug + Display + Default + PartialOrd + Send + Sync + 'static
+    + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> + Div<Output = Self>
+    + AddAssign + SubAssign + MulAssign + DivAssign + Sum + for<'a> Sum<&'a Self>
+{
+    const ZERO: Self;
+
+    /// Convert from `f64`, rounding to the nearest value (or infinity, if it's too large).
+    fn from_f64(value: f64) -> Self;
+
+    /// Convert to `f64`, which is exact.
+    fn to_f64(self) -> f64;
+}
+
+impl Weight for f64 {
+    const ZERO: Self = 0.0;
+
+    fn from_f64(value: f64) -> Self {
+        value
+    }
+
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+impl Weight for f32 {
+    const ZERO: Self = 0.0;
+
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}